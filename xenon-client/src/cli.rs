@@ -0,0 +1,202 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use anyhow::{Context, Result};
+use async_anyhow_logger::catch_context;
+use futures_util::future::join_all;
+use structopt::StructOpt;
+use tokio::time::{timeout, Duration};
+use xenon_client::{
+	config::{self, CONFIG},
+	conn::{self, handshake::Connector},
+	device_main, qrgen, serve_devices, upload_log,
+};
+use xenon_config::{ConnectionConfig, Transport, UserConfig};
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+	/// Connects to paired devices and serves WebDAV for them, until interrupted.
+	Connect {
+		/// Only connect to this device, rather than all of them.
+		device: Option<String>,
+	},
+	/// Pairs with a new device.
+	Pair {
+		/// Print the QR code in the terminal, instead of opening it as an image.
+		#[structopt(long)]
+		qr_terminal: bool,
+	},
+	/// Shows paired devices, and whether they can be reached right now.
+	Status,
+	/// Forgets a paired device.
+	Unpair { device: String },
+	/// Mounts the WebDAV server of a connected device.
+	Mount {
+		/// Which device to mount, if more than one is paired.
+		device: Option<String>,
+	},
+	/// Uploads the log file, and prints where it can be found.
+	UploadLog,
+}
+
+// Devices are picked by name or hostname, or all of them if none is given.
+fn select(config: &UserConfig, device: Option<&str>) -> Result<Vec<usize>> {
+	if config.connections.is_empty() {
+		anyhow::bail!("no devices are paired, run 'xenon-client pair' first");
+	}
+	let indices = config
+		.connections
+		.iter()
+		.enumerate()
+		.filter(|(_, connection)| match device {
+			Some(device) => {
+				connection.display_name().eq_ignore_ascii_case(device)
+					|| connection.hostname.eq_ignore_ascii_case(device)
+			}
+			None => true,
+		})
+		.map(|(index, _)| index)
+		.collect::<Vec<_>>();
+	if indices.is_empty() {
+		anyhow::bail!("no paired device is named '{}'", device.unwrap_or_default());
+	}
+	Ok(indices)
+}
+
+fn describe_transport(connection: &ConnectionConfig) -> String {
+	match &connection.transport {
+		Transport::Tcp => format!("{}:{}", connection.ip, connection.port),
+		Transport::Reverse { listen_port } => format!("reverse, listening on {}", listen_port),
+		Transport::Usb { udid: Some(udid) } => format!("usb, device {}", udid),
+		Transport::Usb { udid: None } => "usb".to_string(),
+	}
+}
+
+async fn connect(device: Option<String>) -> Result<()> {
+	let config = CONFIG.read().await.clone();
+	let indices = select(&config, device.as_deref())?;
+	tokio::spawn(catch_context(
+		"mdns discovery errored",
+		conn::discovery::browse(),
+	));
+	if device.is_none() {
+		serve_devices().await;
+		return Ok(());
+	}
+	let devices = indices
+		.into_iter()
+		.map(|index| {
+			Ok(device_main(
+				config.connections[index].clone(),
+				config.local_port(index)?,
+				config.mount_point(index),
+			))
+		})
+		.collect::<Result<Vec<_>>>()?;
+	join_all(devices).await;
+	Ok(())
+}
+
+async fn pair(qr_terminal: bool) -> Result<()> {
+	let (qr, socket, qr_data) = qrgen::create_qr()?;
+	let image = if qr_terminal {
+		println!("{}", qrgen::render_terminal(&qr_data)?);
+		None
+	} else {
+		Some(qrgen::open_qr_image(&qr_data)?)
+	};
+	println!("Scan the QR code with Xenon on your device.");
+	let paired = qrgen::wait_for_pairing_timeout(qr, socket).await;
+	if let Some(image) = image {
+		let _ = tokio::fs::remove_file(image).await;
+	}
+	let connection = paired?;
+	println!(
+		"Paired with '{}' at {}",
+		connection.display_name(),
+		connection.ip
+	);
+	Ok(())
+}
+
+async fn status() -> Result<()> {
+	tokio::spawn(catch_context(
+		"mdns discovery errored",
+		conn::discovery::browse(),
+	));
+	let config = CONFIG.read().await.clone();
+	for index in select(&config, None)? {
+		let connection = &config.connections[index];
+		println!("{}", connection.display_name());
+		println!("  address:    {}", describe_transport(connection));
+		match config.local_port(index) {
+			Ok(port) => println!("  local port: {}", port),
+			Err(err) => println!("  local port: none ({:#})", err),
+		}
+		let reachable = async {
+			let connector = Connector::new(connection).await?;
+			timeout(
+				STATUS_TIMEOUT,
+				conn::handshake::initiate_connection(&connector, connection),
+			)
+			.await
+			.context("timed out")?
+		};
+		match reachable.await {
+			Ok((_, negotiated)) => println!(
+				"  status:     reachable (protocol {}, {} compression{})",
+				negotiated.version,
+				negotiated.compression.as_str(),
+				if negotiated.multiplex {
+					", multiplexed"
+				} else {
+					""
+				}
+			),
+			Err(err) => println!("  status:     unreachable ({:#})", err),
+		}
+	}
+	Ok(())
+}
+
+async fn unpair(device: String) -> Result<()> {
+	let mut config = CONFIG.write().await;
+	let indices = select(&config, Some(&device))?;
+	for index in indices.into_iter().rev() {
+		let connection = config.remove_connection(index)?;
+		println!("Unpaired '{}'", connection.display_name());
+	}
+	config::save_config(&config)
+}
+
+async fn mount(device: Option<String>) -> Result<()> {
+	let config = CONFIG.read().await.clone();
+	let indices = select(&config, device.as_deref())?;
+	if indices.len() > 1 {
+		anyhow::bail!("more than one device is paired, pick one to mount");
+	}
+	conn::webdav::mount_webdav(
+		config.local_port(indices[0])?,
+		config.mount_point(indices[0]),
+	)
+	.await;
+	Ok(())
+}
+
+pub async fn run(command: Command) -> Result<()> {
+	match command {
+		Command::Connect { device } => connect(device).await,
+		Command::Pair { qr_terminal } => pair(qr_terminal).await,
+		Command::Status => status().await,
+		Command::Unpair { device } => unpair(device).await,
+		Command::Mount { device } => mount(device).await,
+		Command::UploadLog => {
+			println!("{}", upload_log::upload().await?);
+			Ok(())
+		}
+	}
+}
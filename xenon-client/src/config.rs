@@ -51,3 +51,12 @@ pub async fn update_config() -> Result<()> {
 	}
 	Ok(())
 }
+
+pub fn save_config(config: &UserConfig) -> Result<()> {
+	let path = XENON_DIR.config_dir().join("config.toml");
+	std::fs::write(
+		&path,
+		toml::to_string_pretty(config).context("failed to encode new configuration as TOML")?,
+	)
+	.with_context(|| format!("failed to write new configuration to {}", path.display()))
+}
@@ -0,0 +1,95 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::{
+	sync::{Notify, RwLock},
+	time,
+};
+use xenon_tunnel::MDNS_SERVICE_TYPE;
+
+// How long to wait for a server to show up over mDNS before falling back to its stored IP.
+const RESOLVE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+struct DiscoveredServer {
+	fullname: String,
+	addr: SocketAddr,
+}
+
+// Servers we've seen over mDNS, keyed by their public key.
+static DISCOVERED: Lazy<RwLock<HashMap<Vec<u8>, DiscoveredServer>>> =
+	Lazy::new(|| RwLock::new(HashMap::new()));
+static DISCOVERED_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Browses for Xenon servers over mDNS for as long as the client runs.
+pub async fn browse() -> Result<()> {
+	let daemon = ServiceDaemon::new().context("failed to start mdns daemon")?;
+	let receiver = daemon
+		.browse(MDNS_SERVICE_TYPE)
+		.context("failed to browse for xenon servers")?;
+	while let Ok(event) = receiver.recv_async().await {
+		match event {
+			ServiceEvent::ServiceResolved(info) => {
+				let pubkey = match info
+					.get_property_val_str("pubkey")
+					.and_then(|pubkey| base64::decode_config(pubkey, base64::URL_SAFE_NO_PAD).ok())
+				{
+					Some(pubkey) => pubkey,
+					None => {
+						debug!("{} has no valid public key, ignoring", info.get_fullname());
+						continue;
+					}
+				};
+				// Prefer IPv4, as that's what pairing captures too.
+				let ip = match info
+					.get_addresses()
+					.iter()
+					.find(|ip| ip.is_ipv4())
+					.or_else(|| info.get_addresses().iter().next())
+				{
+					Some(ip) => *ip,
+					None => continue,
+				};
+				let addr = SocketAddr::new(ip, info.get_port());
+				debug!("discovered {} at {}", info.get_fullname(), addr);
+				DISCOVERED.write().await.insert(
+					pubkey,
+					DiscoveredServer {
+						fullname: info.get_fullname().to_string(),
+						addr,
+					},
+				);
+				DISCOVERED_CHANGED.notify_waiters();
+			}
+			ServiceEvent::ServiceRemoved(_, fullname) => {
+				debug!("{} is no longer advertised", fullname);
+				DISCOVERED
+					.write()
+					.await
+					.retain(|_, server| server.fullname != fullname);
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+/// Looks up the current address of the server with the given public key.
+pub async fn resolve(pubkey: &[u8]) -> Option<SocketAddr> {
+	time::timeout(RESOLVE_TIMEOUT, async {
+		loop {
+			let changed = DISCOVERED_CHANGED.notified();
+			if let Some(server) = DISCOVERED.read().await.get(pubkey) {
+				return server.addr;
+			}
+			changed.await;
+		}
+	})
+	.await
+	.ok()
+}
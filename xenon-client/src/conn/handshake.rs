@@ -3,44 +3,132 @@
 	All rights reserved.
 */
 
-use crate::{
-	config::{update_config, CONFIG},
-	keys,
-};
+use crate::{config::CONFIG, keys};
 use anyhow::{Context, Result};
-use async_anyhow_logger::catch_context;
 use snow::Builder;
-use tokio::{net::TcpStream, time};
-use xenon_tunnel::{handshake, net, EncryptedTcpStream, NOISE_PARAMS};
+use std::net::SocketAddr;
+use tokio::{
+	net::{TcpListener, TcpStream},
+	time,
+};
+use xenon_config::{ConnectionConfig, Transport};
+use xenon_tunnel::{
+	handshake::{self, Features, Negotiated},
+	net,
+	stream::{compression, AsyncIo},
+	EncryptedStream, NOISE_PARAMS,
+};
+
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+
+/// Where a device's connections come from, depending on its transport.
+pub enum Connector {
+	Tcp,
+	// Kept open between tunnels, so that the device can always reach us.
+	Reverse(TcpListener),
+	Usb(Option<String>),
+}
+
+impl Connector {
+	pub async fn new(connection: &ConnectionConfig) -> Result<Self> {
+		match connection.transport {
+			Transport::Tcp => Ok(Connector::Tcp),
+			Transport::Reverse { listen_port } => TcpListener::bind(("0.0.0.0", listen_port))
+				.await
+				.map(Connector::Reverse)
+				.with_context(|| format!("failed to listen on port {}", listen_port)),
+			Transport::Usb { ref udid } => Ok(Connector::Usb(udid.clone())),
+		}
+	}
 
-pub async fn initiate_connection() -> Result<()> {
+	async fn connect(&self, connection: &ConnectionConfig) -> Result<(Box<dyn AsyncIo>, String)> {
+		match self {
+			Connector::Tcp => {
+				let addr = match super::discovery::resolve(&connection.pubkey).await {
+					Some(addr) => {
+						debug!(
+							"found '{}' at {} over mdns",
+							connection.display_name(),
+							addr
+						);
+						addr
+					}
+					None => SocketAddr::new(connection.ip, connection.port),
+				};
+				let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+					.await
+					.with_context(|| format!("timed out connecting to {}", addr))?
+					.with_context(|| format!("failed to connect to {}", addr))?;
+				Ok((Box::new(stream), addr.to_string()))
+			}
+			// The device dials us, but we're still the Noise initiator and HTTP/2 client.
+			// Anyone else connecting here can't complete the handshake, as they don't have the device's key.
+			Connector::Reverse(listener) => {
+				debug!(
+					"waiting for '{}' to connect on {:?}",
+					connection.display_name(),
+					listener.local_addr()
+				);
+				let (stream, addr) = listener
+					.accept()
+					.await
+					.context("failed to accept connection")?;
+				Ok((Box::new(stream), addr.to_string()))
+			}
+			Connector::Usb(udid) => {
+				let (stream, device) =
+					super::usbmux::connect_to_device(udid.as_deref(), connection.port).await?;
+				Ok((stream, format!("usb device {}", device.udid)))
+			}
+		}
+	}
+}
+
+pub async fn initiate_connection(
+	connector: &Connector,
+	connection: &ConnectionConfig,
+) -> Result<(EncryptedStream<Box<dyn AsyncIo>>, Negotiated)> {
+	let (stream, addr) = connector.connect(connection).await?;
+	// Reverse listeners take connections from anywhere, so a peer that goes quiet mustn't hold us up.
+	time::timeout(CONNECT_TIMEOUT, run_handshake(stream, &addr, connection))
+		.await
+		.with_context(|| format!("timed out during handshake with {}", addr))?
+}
+
+async fn run_handshake(
+	mut stream: Box<dyn AsyncIo>,
+	addr: &str,
+	connection: &ConnectionConfig,
+) -> Result<(EncryptedStream<Box<dyn AsyncIo>>, Negotiated)> {
 	let mut buf = Vec::<u8>::new();
 	let mut handshake_buf = vec![0u8; 65535];
-	let mut timer = time::interval(time::Duration::from_secs(15));
-	let (mut stream, config, connection) = loop {
-		timer.tick().await;
-		catch_context("failed to update config", update_config()).await;
-		let config = CONFIG.read().await.clone();
-		let connection = match config.connection.clone() {
-			Some(conn) => conn,
-			None => continue,
-		};
-		match TcpStream::connect((connection.ip, connection.port)).await {
-			Ok(stream) => break (stream, config, connection),
-			Err(err) => {
-				warn!(
-					"Failed to connect to {}:{}; trying again in 15 seconds: {:?}",
-					connection.ip, connection.port, err
-				)
-			}
+	let (compression, zstd_level) = {
+		let config = CONFIG.read().await;
+		(
+			config.general.compression.clone(),
+			config.general.zstd_level,
+		)
+	};
+	let features = match compression::parse_preference(&compression) {
+		Ok(compression) => Features::with_compression(&compression),
+		Err(err) => {
+			warn!("invalid compression in config, offering all: {:?}", err);
+			Features::default()
 		}
 	};
-	handshake::write_magic(&mut stream)
+	let negotiated = handshake::send_hello(&mut stream, features)
 		.await
-		.context("failed to initiate handshake")?;
+		.context("failed to negotiate protocol")?;
+	debug!(
+		"negotiated protocol version {} with {}, using {} compression",
+		negotiated.version,
+		addr,
+		negotiated.compression.as_str()
+	);
 
 	let mut blizzard = Builder::new(NOISE_PARAMS.clone())
-		.local_private_key(&keys::CLIENT_KEYPAIR.public)
+		.prologue(&negotiated.prologue)
+		.local_private_key(&keys::CLIENT_KEYPAIR.private)
 		.remote_public_key(&connection.pubkey)
 		.build_initiator()
 		.context("failed to initialize encryption")?;
@@ -51,7 +139,7 @@ pub async fn initiate_connection() -> Result<()> {
 	net::write_msg(&mut stream, &handshake_buf[..len])
 		.await
 		.context("failed to send handshake message 0,2")?;
-	debug!("sent 0,2 -> {}", connection.ip);
+	debug!("sent 0,2 -> {}", addr);
 
 	let msg = net::read_msg(&mut stream, &mut buf)
 		.await
@@ -59,7 +147,7 @@ pub async fn initiate_connection() -> Result<()> {
 	blizzard
 		.read_message(msg, &mut handshake_buf)
 		.context("failed to parse handshake message 2,1")?;
-	debug!("{} -> got 2,1", connection.ip);
+	debug!("{} -> got 2,1", addr);
 
 	let len = blizzard
 		.write_message(&[], &mut handshake_buf)
@@ -67,26 +155,24 @@ pub async fn initiate_connection() -> Result<()> {
 	net::write_msg(&mut stream, &handshake_buf[..len])
 		.await
 		.context("failed to send handshake message 4,5")?;
-	debug!("sent 4,5 -> {}", connection.ip);
+	debug!("sent 4,5 -> {}", addr);
+
+	// The server only knows who we are after 4,5, so it may still turn us away here.
+	handshake::read_verdict(&mut stream).await?;
 
 	info!(
-		"succesfully connected to {}, bringing up webserver",
-		connection.ip
+		"succesfully connected to '{}' at {}",
+		connection.display_name(),
+		addr
 	);
-	if config.general.notifications {
-		if let Err(err) = notifica::notify(
-			"Xenon connected",
-			&format!("Connected to '{}' at {}:{} successfully!\nHosting WebDAV server on localhost port {}.", connection.hostname,
-			connection.ip,
-			connection.port,
-			config.general.port)
-		).context("failed to send notification") {
-			warn!("failed to send notification: {:?}", err);
-		}
-	}
 
-	let stream = EncryptedTcpStream::new(blizzard.into_transport_mode().unwrap(), stream);
-	super::http::http_forwarder(stream)
-		.await
-		.context("http forwarder errored")
+	let mut stream = EncryptedStream::new(
+		blizzard
+			.into_transport_mode()
+			.context("failed to finalize encrypted connection")?,
+		stream,
+		compression::from_algorithm(negotiated.compression, zstd_level),
+	);
+	stream.set_rekey_policy(negotiated.rekey_policy());
+	Ok((stream, negotiated))
 }
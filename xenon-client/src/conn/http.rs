@@ -5,55 +5,294 @@
 
 use crate::config::CONFIG;
 use anyhow::{Context, Result};
-use http::Request;
+use async_anyhow_logger::catch_context;
+use futures_util::future::pending;
+use http::{Request, Response, StatusCode};
 use hyper::{
-	client::conn::Builder as HyperBuilder,
+	client::conn::{Builder as HyperBuilder, SendRequest},
 	service::{make_service_fn, service_fn},
 	Body, Server,
 };
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{sync::Mutex, time, try_join};
-use xenon_tunnel::EncryptedTcpStream;
+use std::{
+	convert::Infallible,
+	mem::{discriminant, Discriminant},
+	net::SocketAddr,
+	sync::Arc,
+};
+use tokio::{
+	sync::{watch, Mutex},
+	task::JoinHandle,
+	time, try_join,
+};
+use xenon_config::ConnectionConfig;
+use xenon_tunnel::{
+	control,
+	handshake::{Negotiated, Rejection},
+	mux::{Multiplexer, MuxStream, Role, Service},
+	stream::AsyncIo,
+	EncryptedStream,
+};
+
+type Tunnel = Option<Arc<Mutex<SendRequest<Body>>>>;
+
+const INITIAL_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+// How long a local request waits for the tunnel to come back before giving up with a 503.
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+fn unavailable(reason: &'static str) -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::SERVICE_UNAVAILABLE)
+		.header("Retry-After", "5")
+		.body(Body::from(reason))
+		.expect("failed to build 503 response")
+}
+
+async fn wait_for_tunnel(mut tunnel: watch::Receiver<Tunnel>) -> Tunnel {
+	loop {
+		if let Some(sender) = tunnel.borrow().clone() {
+			return Some(sender);
+		}
+		if tunnel.changed().await.is_err() {
+			return None;
+		}
+	}
+}
+
+async fn forward_request(tunnel: watch::Receiver<Tunnel>, req: Request<Body>) -> Response<Body> {
+	let sender = match time::timeout(REQUEST_TIMEOUT, wait_for_tunnel(tunnel)).await {
+		Ok(Some(sender)) => sender,
+		_ => return unavailable("not connected to xenon-server, reconnecting"),
+	};
+	// Only hold the lock while queueing the request, so that HTTP/2 can multiplex responses.
+	let response = sender.lock().await.send_request(req);
+	match response.await {
+		Ok(response) => response,
+		Err(err) => {
+			warn!("forwarding request to xenon-server failed: {:?}", err);
+			unavailable("connection to xenon-server was lost, reconnecting")
+		}
+	}
+}
+
+async fn notify_connected(connection: &ConnectionConfig, port: u16) {
+	if !CONFIG.read().await.general.notifications {
+		return;
+	}
+	if let Err(err) = notifica::notify(
+		"Xenon connected",
+		&format!(
+			"Connected to '{}' at {}:{} successfully!\nHosting WebDAV server on localhost port {}.",
+			connection.display_name(),
+			connection.ip,
+			connection.port,
+			port
+		),
+	)
+	.context("failed to send notification")
+	{
+		warn!("failed to send notification: {:?}", err);
+	}
+}
+
+async fn notify_rejected(connection: &ConnectionConfig, rejection: &Rejection) {
+	if !CONFIG.read().await.general.notifications {
+		return;
+	}
+	if let Err(err) = notifica::notify(
+		"Xenon connection rejected",
+		&format!(
+			"'{}' refused the connection: {}",
+			connection.display_name(),
+			rejection
+		),
+	)
+	.context("failed to send notification")
+	{
+		warn!("failed to send notification: {:?}", err);
+	}
+}
 
-pub async fn http_forwarder(stream: EncryptedTcpStream) -> Result<()> {
-	let (request_sender, connection) = HyperBuilder::new()
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+	fn drop(&mut self) {
+		self.0.abort();
+	}
+}
+
+// With multiplexing, HTTP/2 gets a stream of its own, next to a control stream for keepalives.
+fn split_tunnel(
+	stream: EncryptedStream<Box<dyn AsyncIo>>,
+	multiplex: bool,
+) -> Result<(Box<dyn AsyncIo>, Option<(MuxStream, AbortOnDrop)>)> {
+	if !multiplex {
+		return Ok((Box::new(stream), None));
+	}
+	let (mux, _incoming, driver) = Multiplexer::new(stream, Role::Initiator);
+	let driver = AbortOnDrop(tokio::spawn(catch_context("tunnel errored", async move {
+		driver.await.context("multiplexer errored")
+	})));
+	let http = mux
+		.open(Service::Http)
+		.context("failed to open http stream")?;
+	let control = mux
+		.open(Service::Control)
+		.context("failed to open control stream")?;
+	Ok((Box::new(http), Some((control, driver))))
+}
+
+// Serves requests over a freshly connected tunnel until it goes down. Only errors if it never came up.
+async fn run_tunnel(
+	connection: &ConnectionConfig,
+	port: u16,
+	mount_point: &Option<String>,
+	mounted: &mut bool,
+	tunnel: &watch::Sender<Tunnel>,
+	stream: EncryptedStream<Box<dyn AsyncIo>>,
+	negotiated: Negotiated,
+) -> Result<()> {
+	let (stream, multiplexed) = split_tunnel(stream, negotiated.multiplex)?;
+	let (request_sender, http_connection) = HyperBuilder::new()
 		.http2_only(true)
-		.http2_max_frame_size(xenon_tunnel::MAX_FRAME_SIZE)
-		.handshake::<EncryptedTcpStream, Body>(stream)
+		.http2_max_frame_size(negotiated.max_frame_size)
+		.handshake::<Box<dyn AsyncIo>, Body>(stream)
 		.await
-		.context("failed to build HTTP/2 server")?;
+		.context("failed to set up HTTP/2")?;
+	let _ = tunnel.send(Some(Arc::new(Mutex::new(request_sender))));
+	info!(
+		"tunnel to '{}' is up, serving WebDAV on port {}",
+		connection.display_name(),
+		port
+	);
+	notify_connected(connection, port).await;
+	if !*mounted {
+		*mounted = true;
+		let mount_point = mount_point.clone();
+		tokio::spawn(async move {
+			time::sleep(time::Duration::from_secs(1)).await;
+			super::webdav::mount_webdav(port, mount_point).await;
+		});
+	}
+	// Dropping the driver at the end of this closes the tunnel, if the server hasn't already.
+	let (control, _driver) = match multiplexed {
+		Some((control, driver)) => (Some(control), Some(driver)),
+		None => (None, None),
+	};
+	let keepalive = async move {
+		match control {
+			Some(control) => control::keepalive(control).await,
+			None => pending().await,
+		}
+	};
+	tokio::select! {
+		result = http_connection => {
+			if let Err(err) = result {
+				warn!(
+					"http connection to '{}' errored: {:?}",
+					connection.display_name(),
+					err
+				);
+			}
+		}
+		result = keepalive => {
+			if let Err(err) = result {
+				warn!(
+					"tunnel to '{}' stopped responding: {:?}",
+					connection.display_name(),
+					err
+				);
+			}
+		}
+	}
+	let _ = tunnel.send(None);
+	Ok(())
+}
+
+// Keeps a tunnel to the server up for as long as the local server runs, reconnecting with exponential backoff.
+async fn tunnel_task(
+	connection: ConnectionConfig,
+	port: u16,
+	mount_point: Option<String>,
+	tunnel: watch::Sender<Tunnel>,
+) -> Result<()> {
+	let connector = super::handshake::Connector::new(&connection).await?;
+	let mut backoff = INITIAL_BACKOFF;
+	let mut mounted = false;
+	// Only notify about each kind of rejection once, rather than on every retry.
+	let mut last_rejection = None::<Discriminant<Rejection>>;
+	loop {
+		match super::handshake::initiate_connection(&connector, &connection).await {
+			Ok((stream, negotiated)) => {
+				last_rejection = None;
+				match run_tunnel(
+					&connection,
+					port,
+					&mount_point,
+					&mut mounted,
+					&tunnel,
+					stream,
+					negotiated,
+				)
+				.await
+				{
+					Ok(()) => backoff = INITIAL_BACKOFF,
+					Err(err) => warn!(
+						"failed to set up tunnel to '{}': {:?}",
+						connection.display_name(),
+						err
+					),
+				}
+			}
+			Err(err) => {
+				warn!(
+					"failed to connect to '{}': {:?}",
+					connection.display_name(),
+					err
+				);
+				if let Some(rejection) = err.downcast_ref::<Rejection>() {
+					if last_rejection != Some(discriminant(rejection)) {
+						notify_rejected(&connection, rejection).await;
+						last_rejection = Some(discriminant(rejection));
+					}
+				}
+			}
+		}
+		warn!(
+			"tunnel to '{}' is down, reconnecting in {} seconds",
+			connection.display_name(),
+			backoff.as_secs()
+		);
+		time::sleep(backoff).await;
+		backoff = (backoff * 2).min(MAX_BACKOFF);
+	}
+}
 
-	let request_sender = Arc::new(Mutex::new(request_sender));
+pub async fn http_forwarder(
+	connection: ConnectionConfig,
+	port: u16,
+	mount_point: Option<String>,
+) -> Result<()> {
+	let (tunnel_tx, tunnel_rx) = watch::channel::<Tunnel>(None);
 
 	let make_svc = make_service_fn(move |_conn| {
-		let request_sender = request_sender.clone();
+		let tunnel = tunnel_rx.clone();
 		async {
-			Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
-				let request_sender = request_sender.clone();
-				async move {
-					let request_sender = request_sender.clone();
-					let mut sender = request_sender.lock().await;
-					sender.send_request(req).await
-				}
+			Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+				let tunnel = tunnel.clone();
+				async move { Ok::<_, Infallible>(forward_request(tunnel, req).await) }
 			}))
 		}
 	});
 
-	let addr = SocketAddr::from(([127, 0, 0, 1], CONFIG.read().await.general.port));
-	let server = Server::bind(&addr).serve(make_svc);
-
-	tokio::spawn(async move {
-		time::sleep(time::Duration::from_secs(1)).await;
-		super::webdav::mount_webdav().await;
-	});
+	let addr = SocketAddr::from(([127, 0, 0, 1], port));
+	let server = Server::try_bind(&addr)
+		.with_context(|| format!("failed to bind local webdav server to {}", addr))?
+		.serve(make_svc);
 
 	try_join!(
 		async move { server.await.context("local http server errored") },
-		async move {
-			connection
-				.await
-				.with_context(|| format!("http connection to xenon-server at [{}] errored", addr))
-		}
+		tunnel_task(connection, port, mount_point, tunnel_tx)
 	)
 	.context("http webdav bridge errored")
 	.map(|_| ())
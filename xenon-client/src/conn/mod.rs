@@ -3,6 +3,8 @@
 	All rights reserved.
 */
 
+pub mod discovery;
 pub mod handshake;
 pub mod http;
+pub mod usbmux;
 pub mod webdav;
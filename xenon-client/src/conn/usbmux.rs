@@ -0,0 +1,316 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::Cursor;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use xenon_tunnel::stream::AsyncIo;
+
+#[cfg(not(target_os = "windows"))]
+pub const USBMUXD_SOCKET: &str = "/var/run/usbmuxd";
+// Apple Mobile Device Support listens over TCP on Windows instead.
+#[cfg(target_os = "windows")]
+pub const USBMUXD_ADDRESS: &str = "127.0.0.1:27015";
+
+// Every message is a 16-byte little-endian header followed by an XML plist:
+// [total length][protocol version, 1 = plist][message type, 8 = plist][tag]
+const HEADER_LEN: usize = 16;
+const PLIST_VERSION: u32 = 1;
+const PLIST_MESSAGE: u32 = 8;
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+const PROG_NAME: &str = "xenon-client";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct Request {
+	message_type: String,
+	prog_name: String,
+	client_version_string: String,
+	#[serde(rename = "DeviceID", default, skip_serializing_if = "Option::is_none")]
+	device_id: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	port_number: Option<u16>,
+}
+
+impl Request {
+	fn new(message_type: &str) -> Self {
+		Self {
+			message_type: message_type.to_string(),
+			prog_name: PROG_NAME.to_string(),
+			client_version_string: env!("CARGO_PKG_VERSION").to_string(),
+			device_id: None,
+			port_number: None,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ResultReply {
+	number: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceListReply {
+	device_list: Vec<DeviceEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceEntry {
+	#[serde(rename = "DeviceID")]
+	device_id: u64,
+	properties: DeviceProperties,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceProperties {
+	#[serde(default)]
+	connection_type: String,
+	#[serde(default)]
+	serial_number: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+	pub id: u64,
+	pub udid: String,
+	pub connection_type: String,
+}
+
+async fn send<S: AsyncWrite + Unpin, T: Serialize>(
+	socket: &mut S,
+	tag: u32,
+	msg: &T,
+) -> Result<()> {
+	let mut payload = Vec::new();
+	plist::to_writer_xml(&mut payload, msg).context("failed to encode usbmuxd message")?;
+	let mut msg = Vec::with_capacity(HEADER_LEN + payload.len());
+	msg.extend_from_slice(&((HEADER_LEN + payload.len()) as u32).to_le_bytes());
+	msg.extend_from_slice(&PLIST_VERSION.to_le_bytes());
+	msg.extend_from_slice(&PLIST_MESSAGE.to_le_bytes());
+	msg.extend_from_slice(&tag.to_le_bytes());
+	msg.extend_from_slice(&payload);
+	socket.write_all(&msg).await?;
+	socket.flush().await?;
+	Ok(())
+}
+
+async fn recv<S: AsyncRead + Unpin, T: DeserializeOwned>(socket: &mut S) -> Result<(u32, T)> {
+	let mut header = [0u8; HEADER_LEN];
+	socket
+		.read_exact(&mut header)
+		.await
+		.context("failed to read usbmuxd message")?;
+	let field = |index: usize| {
+		let mut field = [0u8; 4];
+		field.copy_from_slice(&header[index * 4..index * 4 + 4]);
+		u32::from_le_bytes(field)
+	};
+	let (len, message_type, tag) = (field(0) as usize, field(2), field(3));
+	if message_type != PLIST_MESSAGE {
+		anyhow::bail!("unexpected usbmuxd message type {}", message_type);
+	}
+	if len < HEADER_LEN || len > MAX_MESSAGE_SIZE {
+		anyhow::bail!("invalid usbmuxd message length {}", len);
+	}
+	let mut payload = vec![0u8; len - HEADER_LEN];
+	socket
+		.read_exact(&mut payload)
+		.await
+		.context("failed to read usbmuxd message")?;
+	let msg =
+		plist::from_reader(Cursor::new(payload)).context("failed to decode usbmuxd message")?;
+	Ok((tag, msg))
+}
+
+async fn request<S, T>(socket: &mut S, tag: u32, request: &Request) -> Result<T>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	T: DeserializeOwned,
+{
+	send(socket, tag, request).await?;
+	let (reply_tag, reply) = recv(socket).await?;
+	if reply_tag != tag {
+		anyhow::bail!("usbmuxd replied to {}, expected {}", reply_tag, tag);
+	}
+	Ok(reply)
+}
+
+/// Opens a new connection to the local usbmuxd. Each request needs its own.
+pub async fn connect_usbmuxd() -> Result<Box<dyn AsyncIo>> {
+	#[cfg(not(target_os = "windows"))]
+	let socket = tokio::net::UnixStream::connect(USBMUXD_SOCKET)
+		.await
+		.with_context(|| format!("failed to connect to usbmuxd at {}", USBMUXD_SOCKET))?;
+	#[cfg(target_os = "windows")]
+	let socket = tokio::net::TcpStream::connect(USBMUXD_ADDRESS)
+		.await
+		.with_context(|| format!("failed to connect to usbmuxd at {}", USBMUXD_ADDRESS))?;
+	Ok(Box::new(socket))
+}
+
+pub async fn list_devices<S: AsyncRead + AsyncWrite + Unpin>(
+	socket: &mut S,
+) -> Result<Vec<Device>> {
+	let reply: DeviceListReply = request(socket, 1, &Request::new("ListDevices"))
+		.await
+		.context("failed to list devices")?;
+	Ok(reply
+		.device_list
+		.into_iter()
+		.map(|entry| Device {
+			id: entry.device_id,
+			udid: entry.properties.serial_number,
+			connection_type: entry.properties.connection_type,
+		})
+		.collect())
+}
+
+/// Asks usbmuxd to connect to `port` on the device. Afterwards, the socket is a plain connection to that port.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+	mut socket: S,
+	device_id: u64,
+	port: u16,
+) -> Result<S> {
+	let mut connect = Request::new("Connect");
+	connect.device_id = Some(device_id);
+	// usbmuxd wants the port in network byte order.
+	connect.port_number = Some(port.to_be());
+	let reply: ResultReply = request(&mut socket, 2, &connect)
+		.await
+		.context("failed to connect through usbmuxd")?;
+	match reply.number {
+		0 => Ok(socket),
+		2 => anyhow::bail!("device {} is no longer connected", device_id),
+		3 => anyhow::bail!(
+			"device refused the connection on port {}, is xenon-server running?",
+			port
+		),
+		other => anyhow::bail!("usbmuxd returned error {}", other),
+	}
+}
+
+// Newer versions of usbmuxd also list devices paired over Wi-Fi, which we don't want here.
+fn select_device(devices: Vec<Device>, udid: Option<&str>) -> Result<Device> {
+	devices
+		.into_iter()
+		.filter(|device| device.connection_type == "USB")
+		.find(|device| udid.map_or(true, |udid| device.udid.eq_ignore_ascii_case(udid)))
+		.with_context(|| match udid {
+			Some(udid) => format!("device {} is not connected over USB", udid),
+			None => "no devices are connected over USB".to_string(),
+		})
+}
+
+/// Connects to `port` on the device with the given UDID, or the first one found.
+pub async fn connect_to_device(
+	udid: Option<&str>,
+	port: u16,
+) -> Result<(Box<dyn AsyncIo>, Device)> {
+	let devices = list_devices(&mut connect_usbmuxd().await?).await?;
+	let device = select_device(devices, udid)?;
+	let socket = connect(connect_usbmuxd().await?, device.id, port).await?;
+	Ok((socket, device))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use tokio::net::{UnixListener, UnixStream};
+
+	const DEVICE_ID: u64 = 7;
+	const UDID: &str = "00008030-001a2b3c4d5e6f70";
+
+	// Pretends to be usbmuxd with a single USB device, which echoes everything sent to port 28988.
+	async fn fake_usbmuxd(listener: UnixListener) {
+		loop {
+			let (mut socket, _) = listener.accept().await.unwrap();
+			tokio::spawn(async move {
+				let (tag, msg): (u32, Request) = recv(&mut socket).await.unwrap();
+				match msg.message_type.as_str() {
+					"ListDevices" => {
+						let reply = DeviceListReply {
+							device_list: vec![
+								DeviceEntry {
+									device_id: 3,
+									properties: DeviceProperties {
+										connection_type: "Network".to_string(),
+										serial_number: UDID.to_string(),
+									},
+								},
+								DeviceEntry {
+									device_id: DEVICE_ID,
+									properties: DeviceProperties {
+										connection_type: "USB".to_string(),
+										serial_number: UDID.to_string(),
+									},
+								},
+							],
+						};
+						send(&mut socket, tag, &reply).await.unwrap();
+					}
+					"Connect" => {
+						let accepted = msg.device_id == Some(DEVICE_ID)
+							&& msg.port_number == Some(28988u16.to_be());
+						let number = if accepted { 0 } else { 3 };
+						send(&mut socket, tag, &ResultReply { number })
+							.await
+							.unwrap();
+						if accepted {
+							let (mut reader, mut writer) = socket.split();
+							let _ = tokio::io::copy(&mut reader, &mut writer).await;
+						}
+					}
+					other => panic!("unexpected request {}", other),
+				}
+			});
+		}
+	}
+
+	async fn start() -> (tempfile::TempDir, std::path::PathBuf) {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("usbmuxd");
+		let listener = UnixListener::bind(&path).unwrap();
+		tokio::spawn(fake_usbmuxd(listener));
+		(dir, path)
+	}
+
+	#[tokio::test]
+	async fn lists_and_selects_usb_devices() {
+		let (_dir, path) = start().await;
+		let devices = list_devices(&mut UnixStream::connect(&path).await.unwrap())
+			.await
+			.unwrap();
+		assert_eq!(devices.len(), 2);
+		let device = select_device(devices, Some(&UDID.to_uppercase())).unwrap();
+		assert_eq!(device.id, DEVICE_ID);
+		assert!(select_device(Vec::new(), None).is_err());
+	}
+
+	#[tokio::test]
+	async fn connects_to_device_port() {
+		let (_dir, path) = start().await;
+		let mut socket = connect(UnixStream::connect(&path).await.unwrap(), DEVICE_ID, 28988)
+			.await
+			.unwrap();
+		socket.write_all(b"xenon").await.unwrap();
+		let mut echoed = [0u8; 5];
+		socket.read_exact(&mut echoed).await.unwrap();
+		assert_eq!(&echoed, b"xenon");
+	}
+
+	#[tokio::test]
+	async fn reports_refused_connections() {
+		let (_dir, path) = start().await;
+		let err = connect(UnixStream::connect(&path).await.unwrap(), DEVICE_ID, 1234)
+			.await
+			.unwrap_err();
+		assert!(format!("{:?}", err).contains("refused"));
+	}
+}
@@ -3,20 +3,14 @@
 	All rights reserved.
 */
 
-use crate::config::CONFIG;
-
 #[cfg(target_os = "windows")]
-pub async fn mount_webdav() {
+pub async fn mount_webdav(port: u16, mount_point: Option<String>) {
 	use async_anyhow_logger::catch_context;
 	use std::process::Stdio;
 	use tokio::process::Command;
 
-	let drive_letter = CONFIG
-		.read()
-		.await
-		.general
-		.windows_mount_point
-		.clone()
+	// '*' tells Windows to pick the next free drive letter.
+	let drive_letter = mount_point
 		.filter(|letter| (letter.len() == 2 && letter.ends_with(':')) || letter.len() == 1)
 		.map(|letter| {
 			if letter.len() == 1 {
@@ -25,15 +19,12 @@ pub async fn mount_webdav() {
 				letter
 			}
 		})
-		.unwrap_or_else(|| "W:".to_string());
+		.unwrap_or_else(|| "*".to_string());
 
 	match Command::new("net")
 		.arg("use")
 		.arg(drive_letter)
-		.arg(format!(
-			"http://localhost:{}",
-			CONFIG.read().await.general.port
-		))
+		.arg(format!("http://localhost:{}", port))
 		.stdout(Stdio::piped())
 		.stderr(Stdio::piped())
 		.stdin(Stdio::null())
@@ -60,12 +51,12 @@ pub async fn mount_webdav() {
 }
 
 #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-pub async fn mount_webdav() {
-	info!("You need to manually mount WebDAV at http://localhost:{}, as this OS does not have a standardized method of mounting.", CONFIG.read().await.general.port);
+pub async fn mount_webdav(port: u16, _mount_point: Option<String>) {
+	info!("You need to manually mount WebDAV at http://localhost:{}, as this OS does not have a standardized method of mounting.", port);
 }
 
 #[cfg(target_os = "macos")]
-pub async fn mount_webdav() {
+pub async fn mount_webdav(port: u16, _mount_point: Option<String>) {
 	use std::process::Command;
 
 	let ourself = match std::env::current_exe() {
@@ -83,7 +74,7 @@ pub async fn mount_webdav() {
 
 	match Command::new("osascript")
 		.arg(applescript)
-		.arg(CONFIG.read().await.general.port.to_string())
+		.arg(port.to_string())
 		.spawn()
 	{
 		Ok(_) => (),
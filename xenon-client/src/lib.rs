@@ -0,0 +1,149 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+#![deny(
+	clippy::complexity,
+	clippy::correctness,
+	clippy::perf,
+	clippy::style,
+	unsafe_code
+)]
+
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod conn;
+pub mod keys;
+pub mod qrgen;
+pub mod updater;
+pub mod upload_log;
+pub mod windows;
+
+use crate::config::CONFIG;
+use async_anyhow_logger::catch_context;
+use directories_next::ProjectDirs;
+use once_cell::sync::{Lazy, OnceCell};
+use std::{collections::HashMap, ops::DerefMut};
+use tokio::{
+	runtime::Runtime,
+	sync::Mutex,
+	task::JoinHandle,
+	time::{interval, Duration},
+};
+use xenon_config::ConnectionConfig;
+
+pub static SERVER_TASK: OnceCell<Mutex<JoinHandle<()>>> = OnceCell::new();
+pub static XENON_DIR: Lazy<ProjectDirs> = Lazy::new(|| {
+	let dirs = ProjectDirs::from("me", "aspenuwu", "xenon").expect("no home directory found");
+	if !dirs.config_dir().is_dir() {
+		std::fs::create_dir_all(dirs.config_dir()).expect("failed to create config directory");
+	}
+	if !dirs.data_dir().is_dir() {
+		std::fs::create_dir_all(dirs.data_dir()).expect("failed to create data directory");
+	}
+	dirs
+});
+pub static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.build()
+		.expect("failed to build Tokio runtime")
+});
+
+pub async fn device_main(connection: ConnectionConfig, port: u16, mount_point: Option<String>) {
+	let mut interval = interval(Duration::from_secs(5));
+	loop {
+		interval.tick().await;
+		catch_context(
+			"webdav forwarder errored",
+			conn::http::http_forwarder(connection.clone(), port, mount_point.clone()),
+		)
+		.await;
+		warn!(
+			"webdav server for '{}' stopped, restarting in 5 seconds!",
+			connection.display_name()
+		);
+	}
+}
+
+// A device's tunnel, along with what it was started with. Dropping it stops the tunnel.
+struct Device {
+	settings: (ConnectionConfig, u16, Option<String>),
+	task: JoinHandle<()>,
+}
+
+impl Drop for Device {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+/// Serves every paired device, following config.toml as devices are paired, changed or unpaired.
+pub async fn serve_devices() {
+	let mut devices = HashMap::<Vec<u8>, Device>::new();
+	let mut interval = interval(Duration::from_secs(15));
+	loop {
+		interval.tick().await;
+		catch_context("failed to update config", config::update_config()).await;
+		let config = CONFIG.read().await.clone();
+		let mut wanted = HashMap::new();
+		for (index, connection) in config.connections.iter().enumerate() {
+			match config.local_port(index) {
+				Ok(port) => {
+					wanted.insert(
+						connection.pubkey.clone(),
+						(connection.clone(), port, config.mount_point(index)),
+					);
+				}
+				Err(err) => error!("not serving '{}': {:?}", connection.display_name(), err),
+			}
+		}
+		devices.retain(|pubkey, device| wanted.get(pubkey) == Some(&device.settings));
+		for (pubkey, settings) in wanted {
+			if devices.contains_key(&pubkey) {
+				continue;
+			}
+			info!(
+				"serving '{}' on port {}",
+				settings.0.display_name(),
+				settings.1
+			);
+			let (connection, port, mount_point) = settings.clone();
+			devices.insert(
+				pubkey,
+				Device {
+					settings,
+					task: tokio::spawn(device_main(connection, port, mount_point)),
+				},
+			);
+		}
+	}
+}
+
+pub async fn start_webserver() {
+	if let Some(s) = SERVER_TASK.get() {
+		let mut handle = s.lock().await;
+		let handle = handle.deref_mut();
+		handle.abort();
+		debug!("stopped webserver, waiting for it to die");
+		let _ = handle.await;
+		debug!("old webserver is dead");
+	}
+	let task = RUNTIME.spawn(serve_devices());
+	info!("started webserver");
+	match SERVER_TASK.get() {
+		Some(s) => {
+			*s.lock().await = task;
+		}
+		None => {
+			SERVER_TASK
+				.set(Mutex::new(task))
+				.unwrap_or_else(|_| unreachable!());
+		}
+	}
+}
+
+pub async fn upload_log_to_paste_ee() {}
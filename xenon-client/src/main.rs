@@ -12,60 +12,18 @@
 	unsafe_code
 )]
 
+// Only the tray logs from here.
+#[cfg(feature = "tray")]
 #[macro_use]
 extern crate log;
 
-pub mod config;
-pub mod conn;
-pub mod keys;
-pub mod qrgen;
-pub mod updater;
-pub mod upload_log;
-pub mod windows;
+mod cli;
 
 use anyhow::{Context, Result};
-use async_anyhow_logger::catch_context;
-use directories_next::ProjectDirs;
-use once_cell::sync::{Lazy, OnceCell};
-use std::ops::DerefMut;
-use tokio::{
-	runtime::Runtime,
-	sync::Mutex,
-	task::JoinHandle,
-	time::{interval, Duration},
-};
-use tray_item::TrayItem;
-
-pub static SERVER_TASK: OnceCell<Mutex<JoinHandle<()>>> = OnceCell::new();
-pub static XENON_DIR: Lazy<ProjectDirs> = Lazy::new(|| {
-	let dirs = ProjectDirs::from("me", "aspenuwu", "xenon").expect("no home directory found");
-	if !dirs.config_dir().is_dir() {
-		std::fs::create_dir_all(dirs.config_dir()).expect("failed to create config directory");
-	}
-	if !dirs.data_dir().is_dir() {
-		std::fs::create_dir_all(dirs.data_dir()).expect("failed to create data directory");
-	}
-	dirs
-});
-pub static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
-	tokio::runtime::Builder::new_multi_thread()
-		.enable_all()
-		.build()
-		.expect("failed to build Tokio runtime")
-});
-
-async fn async_main() {
-	let mut interval = interval(Duration::from_secs(5));
-	loop {
-		interval.tick().await;
-		catch_context(
-			"connection to server errored",
-			conn::handshake::initiate_connection(),
-		)
-		.await;
-		warn!("server stopped listening, restarting in 5 seconds!");
-	}
-}
+use structopt::StructOpt;
+use xenon_client::RUNTIME;
+#[cfg(not(debug_assertions))]
+use xenon_client::{config::CONFIG, XENON_DIR};
 
 #[allow(clippy::unnecessary_wraps, clippy::unit_arg)]
 #[cfg(debug_assertions)]
@@ -78,7 +36,6 @@ async fn init_logging() -> Result<()> {
 
 #[cfg(all(not(debug_assertions), not(windows)))]
 async fn init_logging() -> Result<()> {
-	use crate::config::CONFIG;
 	use simplelog::{CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger};
 	use std::fs::File;
 	use xenon_config::LogLevel;
@@ -105,7 +62,6 @@ async fn init_logging() -> Result<()> {
 
 #[cfg(all(not(debug_assertions), windows))]
 async fn init_logging() -> Result<()> {
-	use crate::config::CONFIG;
 	use simplelog::{Config, LevelFilter, WriteLogger};
 	use std::fs::File;
 	use xenon_config::LogLevel;
@@ -126,41 +82,49 @@ async fn init_logging() -> Result<()> {
 	.context("failed to initialize log file")
 }
 
-pub async fn start_webserver() {
-	if let Some(s) = SERVER_TASK.get() {
-		let mut handle = s.lock().await;
-		let handle = handle.deref_mut();
-		handle.abort();
-		debug!("stopped webserver, waiting for it to die");
-		let _ = handle.await;
-		debug!("old webserver is dead");
-	}
-	let task = RUNTIME.spawn(async_main());
-	info!("started webserver");
-	match SERVER_TASK.get() {
-		Some(s) => {
-			*s.lock().await = task;
-		}
-		None => {
-			SERVER_TASK
-				.set(Mutex::new(task))
-				.unwrap_or_else(|_| unreachable!());
-		}
-	}
+/// Runs in the system tray, unless given a command to run headlessly.
+/// Builds without the tray feature connect to every paired device instead.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "xenon-client")]
+struct Opt {
+	#[structopt(subcommand)]
+	command: Option<cli::Command>,
 }
 
-pub async fn upload_log_to_paste_ee() {}
-
 fn main() -> Result<()> {
+	let opt = Opt::from_args();
 	RUNTIME
 		.block_on(init_logging())
 		.context("failed to initialize logger")?;
 	log_panics::init();
 
+	// Headless commands never touch gtk or the tray, so they work without a display.
+	let command = match opt.command {
+		Some(command) => command,
+		#[cfg(feature = "tray")]
+		None => return tray(),
+		#[cfg(not(feature = "tray"))]
+		None => cli::Command::Connect { device: None },
+	};
+	RUNTIME.block_on(cli::run(command))
+}
+
+#[cfg(feature = "tray")]
+fn tray() -> Result<()> {
+	use async_anyhow_logger::catch_context;
+	use tray_item::TrayItem;
+	use xenon_client::{
+		config::CONFIG, conn, qrgen, start_webserver, updater, upload_log, XENON_DIR,
+	};
+
 	RUNTIME.spawn(catch_context(
 		"failed to check for updates",
 		updater::check_for_updates(),
 	));
+	RUNTIME.spawn(catch_context(
+		"mdns discovery errored",
+		conn::discovery::browse(),
+	));
 
 	#[cfg(target_os = "linux")]
 	gtk::init().context("failed to initialize gtk")?;
@@ -179,6 +143,24 @@ fn main() -> Result<()> {
 	})
 	.expect("failed to add tray item");
 
+	// The config is read on click, so devices paired after startup are mounted too.
+	tray.add_menu_item("Mount Devices", move || {
+		RUNTIME.spawn(async {
+			let config = CONFIG.read().await.clone();
+			for (index, connection) in config.connections.iter().enumerate() {
+				match config.local_port(index) {
+					Ok(port) => {
+						conn::webdav::mount_webdav(port, config.mount_point(index)).await;
+					}
+					Err(err) => {
+						error!("failed to mount '{}': {:?}", connection.display_name(), err)
+					}
+				}
+			}
+		});
+	})
+	.expect("failed to add tray item");
+
 	if XENON_DIR.config_dir() == XENON_DIR.data_dir() {
 		tray.add_menu_item("Open Config/Data Folder", move || {
 			if let Err(err) = opener::open(XENON_DIR.config_dir()) {
@@ -3,17 +3,104 @@
 	All rights reserved.
 */
 
-use crate::{config::CONFIG, start_webserver, windows, XENON_DIR};
+use crate::{
+	config::{self, CONFIG},
+	keys, start_webserver, windows,
+};
 use anyhow::{Context, Result};
 use async_anyhow_logger::catch_context;
-use qrcode::QrCode;
-use tokio::time::{timeout, Duration};
-use xenon_config::{ConnectionConfig, QrConnection};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use std::path::PathBuf;
+use tokio::{
+	net::UdpSocket,
+	time::{timeout, Duration},
+};
+use xenon_config::{ConnectionConfig, QrConnection, Transport};
 
-pub async fn qr_connection() -> Result<()> {
-	let (qr, socket) = QrConnection::create().context("failed to initialize QR code")?;
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60 * 5);
 
+/// Creates a new pairing code, along with the socket the server will answer on.
+/// Has to be called from within the runtime, which the socket is registered with.
+pub fn create_qr() -> Result<(QrConnection, UdpSocket, String)> {
+	let (qr, socket) = QrConnection::create(&keys::CLIENT_KEYPAIR.public)
+		.context("failed to initialize QR code")?;
+	socket
+		.set_nonblocking(true)
+		.context("failed to make pairing socket non-blocking")?;
+	let socket = UdpSocket::from_std(socket).context("failed to register pairing socket")?;
 	let qr_data = ["XE42", qr.to_base64().as_str()].join("~");
+	Ok((qr, socket, qr_data))
+}
+
+/// Renders the QR code as text, for terminals without a way to show images.
+pub fn render_terminal(qr_data: &str) -> Result<String> {
+	Ok(QrCode::new(qr_data.as_bytes())
+		.context("failed to create QR code")?
+		.render::<Dense1x2>()
+		.quiet_zone(true)
+		.build())
+}
+
+/// Waits for the server to scan our code, then saves it as a new connection.
+pub async fn wait_for_pairing(qr: QrConnection, socket: UdpSocket) -> Result<ConnectionConfig> {
+	let mut buf = vec![0u8; 4096];
+	loop {
+		let (recv_amt, addr) = socket
+			.recv_from(&mut buf)
+			.await
+			.context("failed to receive bytes")?;
+		if recv_amt < 64 {
+			error!(
+				"received {} bytes, even though we should always get at least 64",
+				recv_amt
+			);
+			continue;
+		}
+		debug!("received {} bytes from {}", recv_amt, addr);
+		if buf[..32] != qr.code {
+			continue;
+		}
+		let (hostname, port, pubkey): (String, u16, Vec<u8>) =
+			rmp_serde::from_slice(&buf[32..]).context("failed to decode msgpack from message")?;
+		let ip = addr.ip();
+		info!(
+			"configuring server: '{}' at {}, public key [{}]",
+			hostname,
+			ip,
+			base64::encode_config(&pubkey, base64::URL_SAFE_NO_PAD)
+		);
+		let config = ConnectionConfig {
+			name: hostname.clone(),
+			ip,
+			port,
+			hostname,
+			pubkey,
+			local_port: None,
+			mount_point: None,
+			transport: Transport::default(),
+		};
+		debug!("writing new connection config: {:#?}", config);
+		let mut global_config = CONFIG.write().await;
+		global_config
+			.add_connection(config.clone())
+			.context("failed to add the new device")?;
+		config::save_config(&global_config)?;
+		return Ok(config);
+	}
+}
+
+/// Like [wait_for_pairing], but gives up if nobody scans the code in time.
+pub async fn wait_for_pairing_timeout(
+	qr: QrConnection,
+	socket: UdpSocket,
+) -> Result<ConnectionConfig> {
+	timeout(PAIRING_TIMEOUT, wait_for_pairing(qr, socket))
+		.await
+		.context("waiting for QR code message timed out")?
+}
+
+/// Saves the QR code as an image and opens it, returning where it was saved.
+pub fn open_qr_image(qr_data: &str) -> Result<PathBuf> {
 	let qr_image = QrCode::new(qr_data.as_bytes())
 		.context("failed to create QR code")?
 		.render::<image::Rgb<u8>>()
@@ -31,6 +118,12 @@ pub async fn qr_connection() -> Result<()> {
 		.context("failed to save rendered QR code to file")?;
 	debug!("qr code saved to {}", tempfile.display());
 	opener::open(&tempfile).context("failed to open qr code image")?;
+	Ok(tempfile)
+}
+
+pub async fn qr_connection() -> Result<()> {
+	let (qr, socket, qr_data) = create_qr()?;
+	let tempfile = open_qr_image(&qr_data)?;
 
 	tokio::spawn(catch_context(
 		"failed to check for windows firewall",
@@ -38,62 +131,12 @@ pub async fn qr_connection() -> Result<()> {
 	));
 
 	tokio::spawn(async move {
-		let timer = Duration::from_secs(60 * 5);
-		let x = timeout(
-			timer,
-			catch_context("failed to receive qr code", async move {
-				let mut buf = vec![0u8; 4096];
-				loop {
-					let (recv_amt, addr) = socket
-						.recv_from(&mut buf)
-						.context("failed to receive bytes")?;
-					if recv_amt < 64 {
-						error!(
-							"received {} bytes, even though we should always get at least 64",
-							recv_amt
-						);
-						continue;
-					}
-					debug!("received {} bytes from {}", recv_amt, addr);
-					if buf[..32] == qr.code {
-						let (hostname, port, pubkey): (String, u16, Vec<u8>) =
-							rmp_serde::from_slice(&buf[32..])
-								.context("failed to decode msgpack from message")?;
-						let ip = addr.ip();
-						info!(
-							"configuring server: '{}' at {}, public key [{}]",
-							hostname,
-							ip,
-							base64::encode_config(&pubkey, base64::URL_SAFE_NO_PAD)
-						);
-						let config = ConnectionConfig {
-							ip,
-							port,
-							hostname,
-							pubkey,
-						};
-						debug!("writing new connection config: {:#?}", config);
-						CONFIG.write().await.connection = Some(config.clone());
-						let path = XENON_DIR.config_dir().join("config.toml");
-						std::fs::write(
-							&path,
-							toml::to_string_pretty(&*CONFIG.read().await)
-								.context("failed to encode new configuration as TOML")?,
-						)
-						.with_context(|| {
-							format!("failed to write new configuration to {}", path.display())
-						})?;
-						let _ = tokio::fs::remove_file(tempfile).await;
-						tokio::spawn(start_webserver());
-						break;
-					}
-				}
-				Ok(())
-			}),
-		)
-		.await;
-		if x.is_err() {
-			warn!("waiting for QR code message timed out");
+		match wait_for_pairing_timeout(qr, socket).await {
+			Ok(_) => {
+				let _ = tokio::fs::remove_file(tempfile).await;
+				tokio::spawn(start_webserver());
+			}
+			Err(err) => warn!("failed to pair: {:?}", err),
 		}
 	});
 
@@ -4,26 +4,24 @@
 */
 
 use crate::XENON_DIR;
+use anyhow::{Context, Result};
 
-pub async fn upload_log() {
+/// Uploads our log file, returning the URL it can be found at.
+pub async fn upload() -> Result<String> {
 	let logfile = XENON_DIR.data_dir().join("xenon-client.log");
-	let logs = match tokio::fs::read_to_string(logfile).await {
-		Ok(o) => o,
-		Err(err) => {
-			let _ = notifica::notify(
-				"Error uploading log file",
-				&format!("Failed to read log file: {:?}", err),
-			);
-			return;
-		}
-	};
-	let url = match xenon_log_upload::upload_log_to_paste("xenon client log".into(), logs).await {
+	let logs = tokio::fs::read_to_string(&logfile)
+		.await
+		.with_context(|| format!("failed to read log file {}", logfile.display()))?;
+	xenon_log_upload::upload_log_to_paste("xenon client log".into(), logs)
+		.await
+		.context("failed to upload log file")
+}
+
+pub async fn upload_log() {
+	let url = match upload().await {
 		Ok(o) => o,
 		Err(err) => {
-			let _ = notifica::notify(
-				"Error uploading log file",
-				&format!("Failed to upload log file: {:?}", err),
-			);
+			let _ = notifica::notify("Error uploading log file", &format!("{:?}", err));
 			return;
 		}
 	};
@@ -0,0 +1,38 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use std::time::{Duration, Instant};
+use xenon_client::conn::discovery;
+use xenon_server::{
+	keys::KEYPAIR,
+	mdns,
+	paths::{self, Paths},
+};
+use xenon_tunnel::XENON_PORT;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resolves_advertised_server() {
+	let root = tempfile::tempdir().unwrap();
+	assert!(paths::set(Paths {
+		config: root.path().join("server"),
+		mobile: root.path().join("mobile"),
+		ipc_socket: root.path().join("xenon.sock"),
+	}));
+	let pubkey = KEYPAIR.read().await.public.clone();
+
+	tokio::spawn(discovery::browse());
+	mdns::advertise().await.unwrap();
+
+	// Each resolve only waits a couple of seconds, so give the announcement a few chances.
+	let deadline = Instant::now() + Duration::from_secs(20);
+	let addr = loop {
+		if let Some(addr) = discovery::resolve(&pubkey).await {
+			break addr;
+		}
+		assert!(Instant::now() < deadline, "server was never discovered");
+	};
+	assert_eq!(addr.port(), XENON_PORT);
+	assert!(discovery::resolve(&[0; 32]).await.is_none());
+}
@@ -0,0 +1,282 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use reqwest::{Client, Method, StatusCode};
+use std::{
+	net::{IpAddr, Ipv4Addr},
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use xenon_client::{
+	config::CONFIG,
+	conn::{
+		handshake::{initiate_connection, Connector},
+		http::http_forwarder,
+	},
+	keys::CLIENT_KEYPAIR,
+};
+use xenon_config::{
+	AuthorizedClient, ConnectionConfig, MountAccess, MountError, MountPermissions, MountStatus,
+	MountType,
+};
+use xenon_server::{
+	clients,
+	keys::KEYPAIR,
+	mount::{self, Mount, DAV_MOUNTS, MOUNT_STATUS},
+	paths::{self, Paths},
+	server,
+};
+
+// Keeps both sides away from the real home directory, keyring and /var/mobile.
+fn isolate(root: &Path) {
+	std::env::set_var("HOME", root.join("home"));
+	std::env::set_var("XDG_CONFIG_HOME", root.join("home/.config"));
+	std::env::set_var("XDG_DATA_HOME", root.join("home/.local/share"));
+	std::env::remove_var("DBUS_SESSION_BUS_ADDRESS");
+	assert!(paths::set(Paths {
+		config: root.join("server"),
+		mobile: root.join("mobile"),
+		ipc_socket: root.join("xenon.sock"),
+	}));
+}
+
+async fn add_mount(root: &Path, name: &str, access: MountAccess) {
+	let files = root.join(name);
+	std::fs::create_dir_all(&files).unwrap();
+	std::fs::write(files.join("existing.txt"), "already here").unwrap();
+	DAV_MOUNTS.write().await.insert(
+		name.to_string(),
+		Mount {
+			handler: mount::create_dav_handler(name, MountType::Path(files.clone())).unwrap(),
+			source: MountType::Path(files),
+			permissions: MountPermissions {
+				access,
+				..MountPermissions::default()
+			},
+		},
+	);
+}
+
+async fn start_server(root: &Path) -> u16 {
+	add_mount(root, "files", MountAccess::ReadWrite).await;
+	add_mount(root, "shared", MountAccess::ReadOnly).await;
+	add_mount(root, "private", MountAccess::Hidden).await;
+	// Mounts that failed to load, as load_mounts would have recorded them.
+	let mut status = MOUNT_STATUS.write().await;
+	for (name, access) in &[
+		("missing", MountAccess::ReadOnly),
+		("secret", MountAccess::Hidden),
+	] {
+		status.insert(
+			name.to_string(),
+			(
+				MountPermissions {
+					access: *access,
+					..MountPermissions::default()
+				},
+				MountStatus::from(&Err::<(), _>(MountError::PathMissing {
+					path: root.join(name),
+				})),
+			),
+		);
+	}
+	drop(status);
+	clients::authorize(AuthorizedClient {
+		pubkey: CLIENT_KEYPAIR.public.clone(),
+		ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+		paired_at: SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap()
+			.as_secs(),
+	})
+	.await
+	.unwrap();
+
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let port = listener.local_addr().unwrap().port();
+	tokio::spawn(server::serve(listener));
+	port
+}
+
+async fn free_port() -> u16 {
+	TcpListener::bind("127.0.0.1:0")
+		.await
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port()
+}
+
+async fn propfind(client: &Client, url: &str) -> String {
+	let response = client
+		.request(Method::from_bytes(b"PROPFIND").unwrap(), url)
+		.header("Depth", "1")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+	response.text().await.unwrap()
+}
+
+// Both sides keep their state in globals, so this all has to happen in a single test.
+#[tokio::test(flavor = "multi_thread")]
+async fn webdav_over_tunnel() {
+	let root = tempfile::tempdir().unwrap();
+	isolate(root.path());
+	CONFIG.write().await.general.notifications = false;
+	let server_port = start_server(root.path()).await;
+
+	let connection = ConnectionConfig {
+		name: "test device".to_string(),
+		ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+		port: server_port,
+		hostname: "localhost".to_string(),
+		pubkey: KEYPAIR.read().await.public.clone(),
+		..ConnectionConfig::default()
+	};
+	let connector = Connector::new(&connection).await.unwrap();
+	let (_, negotiated) = initiate_connection(&connector, &connection).await.unwrap();
+	assert!(negotiated.multiplex);
+
+	let local_port = free_port().await;
+	tokio::spawn(http_forwarder(connection, local_port, None));
+	let client = Client::new();
+	let base = format!("http://127.0.0.1:{}/files", local_port);
+
+	let listing = propfind(&client, &format!("{}/", base)).await;
+	assert!(listing.contains("existing.txt"));
+
+	let response = client
+		.put(&format!("{}/hello.txt", base))
+		.body("hello from the client")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::CREATED);
+	assert_eq!(
+		std::fs::read_to_string(root.path().join("files/hello.txt")).unwrap(),
+		"hello from the client"
+	);
+
+	let response = client
+		.get(&format!("{}/hello.txt", base))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.text().await.unwrap(), "hello from the client");
+	assert!(propfind(&client, &format!("{}/", base))
+		.await
+		.contains("hello.txt"));
+
+	let response = client
+		.delete(&format!("{}/hello.txt", base))
+		.send()
+		.await
+		.unwrap();
+	assert!(response.status().is_success());
+	assert!(!root.path().join("files/hello.txt").exists());
+	let response = client
+		.get(&format!("{}/hello.txt", base))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+	let shared = format!("http://127.0.0.1:{}/shared", local_port);
+	let response = client
+		.get(&format!("{}/existing.txt", shared))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.text().await.unwrap(), "already here");
+	assert!(propfind(&client, &format!("{}/", shared))
+		.await
+		.contains("existing.txt"));
+	// Anything that isn't a read is refused, not just PUT and DELETE.
+	for (method, path) in &[
+		("PUT", "hello.txt"),
+		("DELETE", "existing.txt"),
+		("PATCH", "existing.txt"),
+		("MKCOL", "folder"),
+		("MOVE", "existing.txt"),
+		("COPY", "existing.txt"),
+		("PROPPATCH", "existing.txt"),
+		("LOCK", "existing.txt"),
+	] {
+		let response = client
+			.request(
+				Method::from_bytes(method.as_bytes()).unwrap(),
+				&format!("{}/{}", shared, path),
+			)
+			.header("Destination", format!("{}/copy.txt", shared))
+			.header("Content-Range", "bytes 0-4/*")
+			.body("hello")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", method);
+	}
+	let mut entries = std::fs::read_dir(root.path().join("shared"))
+		.unwrap()
+		.map(|entry| entry.unwrap().file_name())
+		.collect::<Vec<_>>();
+	entries.sort();
+	assert_eq!(entries, ["existing.txt"]);
+	assert_eq!(
+		std::fs::read_to_string(root.path().join("shared/existing.txt")).unwrap(),
+		"already here"
+	);
+
+	let private = format!("http://127.0.0.1:{}/private", local_port);
+	let response = client
+		.get(&format!("{}/existing.txt", private))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let response = client
+		.put(&format!("{}/hello.txt", private))
+		.body("hello from the client")
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	assert!(!root.path().join("private/hello.txt").exists());
+
+	let response = client
+		.get(&format!("http://127.0.0.1:{}/nothing/here", local_port))
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let status: serde_json::Value = client
+		.get(&format!("http://127.0.0.1:{}/.xenon/status", local_port))
+		.send()
+		.await
+		.unwrap()
+		.json()
+		.await
+		.unwrap();
+	// Only the forwarder's own tunnel, not the one used to check the handshake.
+	let connection = &status["connection"];
+	assert_eq!(connection["requests"]["files"], 6);
+	// Refused requests never reach the mount, so they aren't counted.
+	assert_eq!(connection["requests"]["shared"], 2);
+	assert!(connection["requests"]["private"].is_null());
+	assert!(connection["bytes-in"].as_u64().unwrap() > 0);
+	assert!(connection["bytes-out"].as_u64().unwrap() > 0);
+	// Hidden mounts aren't counted, and nothing about the server's setup is given out.
+	assert_eq!(status["mounts"], 2);
+	assert_eq!(
+		status["mount-status"],
+		serde_json::json!({ "missing": { "loaded": false, "error": "path-missing" } })
+	);
+	for key in &["connections", "config-dir", "authorized-clients"] {
+		assert!(status.get(key).is_none(), "{} was exposed", key);
+	}
+}
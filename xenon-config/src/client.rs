@@ -0,0 +1,15 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuthorizedClient {
+	#[serde(with = "crate::Base64")]
+	pub pubkey: Vec<u8>,
+	pub ip: IpAddr,
+	// Seconds since the UNIX epoch.
+	pub paired_at: u64,
+}
@@ -0,0 +1,193 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+// Structured messages start with this, followed by a u32 big-endian length and that much JSON.
+// Anything else on the socket is a legacy NUL-terminated string command.
+pub const IPC_MAGIC: &[u8; 4] = b"XIPC";
+pub const IPC_VERSION: u16 = 1;
+pub const IPC_HEADER_LEN: usize = IPC_MAGIC.len() + std::mem::size_of::<u32>();
+pub const MAX_IPC_MESSAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum IpcCommand {
+	Status,
+	ListMounts,
+	ReloadMounts,
+	RegenerateKeys,
+	Pubkey,
+	Bundles,
+	IcloudBundles,
+	GenerateConfig,
+	ListClients,
+	RevokeClient { pubkey: String },
+	Pair { qr_code: String },
+}
+
+impl IpcCommand {
+	/// Parses a legacy string command. Anything unrecognized is a QR code, as that's what the prefs bundle sends.
+	pub fn from_legacy(command: &str) -> Self {
+		match command {
+			"status" => IpcCommand::Status,
+			"list-mounts" => IpcCommand::ListMounts,
+			"reload-mounts" => IpcCommand::ReloadMounts,
+			"regenerate-keys" => IpcCommand::RegenerateKeys,
+			"pubkey" => IpcCommand::Pubkey,
+			"bundles" => IpcCommand::Bundles,
+			"icloud-bundles" => IpcCommand::IcloudBundles,
+			"generate-config" => IpcCommand::GenerateConfig,
+			"list-clients" => IpcCommand::ListClients,
+			cmd if cmd.starts_with("revoke-client ") => IpcCommand::RevokeClient {
+				pubkey: cmd["revoke-client ".len()..].to_string(),
+			},
+			cmd => IpcCommand::Pair {
+				qr_code: cmd.to_string(),
+			},
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IpcRequest {
+	pub version: u16,
+	#[serde(flatten)]
+	pub command: IpcCommand,
+}
+
+impl IpcRequest {
+	pub fn new(command: IpcCommand) -> Self {
+		Self {
+			version: IPC_VERSION,
+			command,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IpcResponse {
+	pub version: u16,
+	#[serde(flatten)]
+	pub result: IpcResult,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum IpcResult {
+	Ok { result: Value },
+	Error { message: String },
+}
+
+impl IpcResponse {
+	pub fn ok(result: Value) -> Self {
+		Self {
+			version: IPC_VERSION,
+			result: IpcResult::Ok { result },
+		}
+	}
+
+	pub fn error(message: String) -> Self {
+		Self {
+			version: IPC_VERSION,
+			result: IpcResult::Error { message },
+		}
+	}
+
+	pub fn into_result(self) -> Result<Value> {
+		match self.result {
+			IpcResult::Ok { result } => Ok(result),
+			IpcResult::Error { message } => Err(anyhow::anyhow!(message)),
+		}
+	}
+}
+
+/// Frames a message with the magic and its length.
+pub fn encode_ipc_message<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+	let json = serde_json::to_vec(msg).context("failed to encode IPC message")?;
+	if json.len() > MAX_IPC_MESSAGE_SIZE {
+		anyhow::bail!("IPC message is too large ({} bytes)", json.len());
+	}
+	let mut out = Vec::with_capacity(IPC_HEADER_LEN + json.len());
+	out.extend_from_slice(IPC_MAGIC);
+	out.extend_from_slice(&(json.len() as u32).to_be_bytes());
+	out.extend_from_slice(&json);
+	Ok(out)
+}
+
+/// Checks a message header, returning how long the JSON following it is.
+pub fn ipc_message_len(header: &[u8; IPC_HEADER_LEN]) -> Result<usize> {
+	if &header[..IPC_MAGIC.len()] != IPC_MAGIC {
+		anyhow::bail!("not a structured IPC message");
+	}
+	let mut len = [0u8; 4];
+	len.copy_from_slice(&header[IPC_MAGIC.len()..]);
+	let len = u32::from_be_bytes(len) as usize;
+	if len > MAX_IPC_MESSAGE_SIZE {
+		anyhow::bail!("IPC message is too large ({} bytes)", len);
+	}
+	Ok(len)
+}
+
+pub fn decode_ipc_message<T: DeserializeOwned>(json: &[u8]) -> Result<T> {
+	serde_json::from_slice(json).context("failed to decode IPC message")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn request_round_trip() {
+		let request = IpcRequest::new(IpcCommand::RevokeClient {
+			pubkey: "abc".to_string(),
+		});
+		let encoded = encode_ipc_message(&request).unwrap();
+		let mut header = [0u8; IPC_HEADER_LEN];
+		header.copy_from_slice(&encoded[..IPC_HEADER_LEN]);
+		let len = ipc_message_len(&header).unwrap();
+		assert_eq!(len, encoded.len() - IPC_HEADER_LEN);
+		let decoded: IpcRequest = decode_ipc_message(&encoded[IPC_HEADER_LEN..]).unwrap();
+		assert_eq!(decoded.version, IPC_VERSION);
+		assert_eq!(decoded.command, request.command);
+	}
+
+	#[test]
+	fn unit_commands_are_just_a_tag() {
+		let decoded: IpcRequest =
+			decode_ipc_message(br#"{"version":1,"command":"list-mounts"}"#).unwrap();
+		assert_eq!(decoded.command, IpcCommand::ListMounts);
+	}
+
+	#[test]
+	fn legacy_commands() {
+		assert_eq!(IpcCommand::from_legacy("pubkey"), IpcCommand::Pubkey);
+		assert_eq!(
+			IpcCommand::from_legacy("revoke-client abc"),
+			IpcCommand::RevokeClient {
+				pubkey: "abc".to_string()
+			}
+		);
+		assert_eq!(
+			IpcCommand::from_legacy("XE42~code"),
+			IpcCommand::Pair {
+				qr_code: "XE42~code".to_string()
+			}
+		);
+	}
+
+	#[test]
+	fn errors_carry_a_message() {
+		let response: IpcResponse =
+			decode_ipc_message(br#"{"version":1,"status":"error","message":"no such client"}"#)
+				.unwrap();
+		assert_eq!(
+			response.into_result().unwrap_err().to_string(),
+			"no such client"
+		);
+	}
+}
@@ -8,11 +8,15 @@ extern crate serde;
 #[macro_use]
 extern crate base64_serde;
 
+pub mod client;
+pub mod ipc;
 pub mod keypair;
 pub mod mount;
 pub mod qr;
 pub mod user;
 
+pub use client::*;
+pub use ipc::*;
 pub use keypair::*;
 pub use mount::*;
 pub use qr::*;
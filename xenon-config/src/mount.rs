@@ -3,9 +3,9 @@
 	All rights reserved.
 */
 
-use std::path::PathBuf;
+use std::{collections::HashMap, fmt, path::PathBuf};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum MountPreset {
 	Photos,
@@ -14,7 +14,7 @@ pub enum MountPreset {
 	Documents,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum MountType {
 	Path(PathBuf),
@@ -22,3 +22,127 @@ pub enum MountType {
 	Bundle(String),
 	Preset(MountPreset),
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MountAccess {
+	ReadWrite,
+	ReadOnly,
+	Hidden,
+}
+
+impl Default for MountAccess {
+	fn default() -> Self {
+		MountAccess::ReadWrite
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct MountPermissions {
+	#[serde(default)]
+	pub access: MountAccess,
+	// Per-client overrides of `access`, keyed by the client's base64 public key.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub clients: HashMap<String, MountAccess>,
+}
+
+impl MountPermissions {
+	pub fn access_for(&self, client: &[u8]) -> MountAccess {
+		self.clients
+			.get(&base64::encode_config(client, base64::URL_SAFE_NO_PAD))
+			.copied()
+			.unwrap_or(self.access)
+	}
+}
+
+// An entry in mounts.json. The permission fields are optional, so plain `MountType` entries still parse.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MountConfig {
+	#[serde(flatten)]
+	pub mount: MountType,
+	#[serde(flatten)]
+	pub permissions: MountPermissions,
+}
+
+/// Why a mount in mounts.json isn't being served.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "error", rename_all = "kebab-case")]
+pub enum MountError {
+	InvalidName,
+	BundleNotFound { bundle: String },
+	PathMissing { path: PathBuf },
+	PermissionDenied { path: PathBuf },
+}
+
+impl MountError {
+	/// What went wrong, without the paths or bundles involved.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			MountError::InvalidName => "invalid-name",
+			MountError::BundleNotFound { .. } => "bundle-not-found",
+			MountError::PathMissing { .. } => "path-missing",
+			MountError::PermissionDenied { .. } => "permission-denied",
+		}
+	}
+}
+
+impl fmt::Display for MountError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			MountError::InvalidName => write!(f, "invalid name"),
+			MountError::BundleNotFound { bundle } => write!(f, "bundle {} not found", bundle),
+			MountError::PathMissing { path } => write!(f, "{} doesn't exist", path.display()),
+			MountError::PermissionDenied { path } => {
+				write!(f, "permission denied for {}", path.display())
+			}
+		}
+	}
+}
+
+impl std::error::Error for MountError {}
+
+/// How loading a single mount went, as reported over IPC and the status endpoint.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MountStatus {
+	pub loaded: bool,
+	#[serde(flatten)]
+	pub error: Option<MountError>,
+}
+
+impl<T> From<&Result<T, MountError>> for MountStatus {
+	fn from(result: &Result<T, MountError>) -> Self {
+		MountStatus {
+			loaded: result.is_ok(),
+			error: result.as_ref().err().cloned(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mount_status_round_trip() {
+		let loaded = MountStatus::from(&Ok::<_, MountError>(()));
+		assert_eq!(
+			serde_json::to_string(&loaded).unwrap(),
+			r#"{"loaded":true}"#
+		);
+		assert_eq!(
+			serde_json::from_str::<MountStatus>(r#"{"loaded":true}"#).unwrap(),
+			loaded
+		);
+
+		let missing = MountStatus::from(&Err::<(), _>(MountError::BundleNotFound {
+			bundle: "com.example.app".to_string(),
+		}));
+		let json = serde_json::to_string(&missing).unwrap();
+		assert_eq!(
+			json,
+			r#"{"loaded":false,"error":"bundle-not-found","bundle":"com.example.app"}"#
+		);
+		assert_eq!(serde_json::from_str::<MountStatus>(&json).unwrap(), missing);
+		assert_eq!(missing.error.unwrap().kind(), "bundle-not-found");
+	}
+}
@@ -24,16 +24,21 @@ pub struct QrConnection {
 	pub ip: IpAddr,
 	pub port: u16,
 	pub code: [u8; 32],
+	// The client's static public key, which the server adds to its list of authorized clients.
+	// QR codes from older clients don't have this, and can't be paired.
+	#[serde(default)]
+	pub pubkey: Vec<u8>,
 }
 
 impl QrConnection {
-	pub fn create() -> Result<(Self, UdpSocket)> {
+	pub fn create(pubkey: &[u8]) -> Result<(Self, UdpSocket)> {
 		let ip = get_local_ip().context("failed to find local ip")?;
 		let socket = UdpSocket::bind(format!("{}:0", ip)).context("failed to open udp socket")?;
 		let qr = Self {
 			ip,
 			port: socket.local_addr().context("failed to get port")?.port(),
 			code: rand::random(),
+			pubkey: pubkey.to_vec(),
 		};
 		Ok((qr, socket))
 	}
@@ -3,7 +3,12 @@
 	All rights reserved.
 */
 
-use std::net::{IpAddr, Ipv4Addr};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::{
+	convert::TryFrom,
+	net::{IpAddr, Ipv4Addr},
+};
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
@@ -28,11 +33,139 @@ const fn default_notifications() -> bool {
 	true
 }
 
+const fn default_zstd_level() -> i32 {
+	3
+}
+
+// Older configs only have a single [connection] table, newer ones have a [[connection]] array.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<ConnectionConfig>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum OneOrMany {
+		One(ConnectionConfig),
+		Many(Vec<ConnectionConfig>),
+	}
+
+	Ok(match OneOrMany::deserialize(deserializer)? {
+		OneOrMany::One(connection) => vec![connection],
+		OneOrMany::Many(connections) => connections,
+	})
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct UserConfig {
 	#[serde(flatten)]
 	pub general: GeneralConfig,
-	pub connection: Option<ConnectionConfig>,
+	#[serde(
+		default,
+		rename = "connection",
+		deserialize_with = "one_or_many",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub connections: Vec<ConnectionConfig>,
+}
+
+impl UserConfig {
+	/// The local port the WebDAV server for the connection at `index` listens on.
+	/// Pairing saves a port for every device, connections without one are given consecutive ports starting at `port`.
+	pub fn local_port(&self, index: usize) -> Result<u16> {
+		if let Some(port) = self
+			.connections
+			.get(index)
+			.and_then(|connection| connection.local_port)
+		{
+			return Ok(port);
+		}
+		u16::try_from(index)
+			.ok()
+			.and_then(|index| self.general.port.checked_add(index))
+			.with_context(|| {
+				format!(
+					"there's no port left for device {} after port {}",
+					index, self.general.port
+				)
+			})
+	}
+
+	/// The Windows drive letter the connection at `index` should be mounted to.
+	/// Only the device on the global `port` falls back to the global `mount-point`.
+	pub fn mount_point(&self, index: usize) -> Option<String> {
+		let connection = self.connections.get(index)?;
+		match connection.mount_point.clone() {
+			Some(mount_point) => Some(mount_point),
+			None if self.local_port(index).ok() == Some(self.general.port) => self
+				.general
+				.windows_mount_point
+				.clone()
+				.or_else(|| Some("W:".to_string())),
+			None => None,
+		}
+	}
+
+	// Saves the ports that connections have been using, so removing one doesn't move the ones after it.
+	fn pin_local_ports(&mut self) -> Result<()> {
+		for index in 0..self.connections.len() {
+			if self.connections[index].local_port.is_none() {
+				self.connections[index].local_port = Some(self.local_port(index)?);
+			}
+		}
+		Ok(())
+	}
+
+	/// Adds a newly paired device, replacing any existing connection with the same public key.
+	/// New devices are given the first port from `port` onwards that no other device uses.
+	pub fn add_connection(&mut self, mut connection: ConnectionConfig) -> Result<()> {
+		self.pin_local_ports()?;
+		if let Some(existing) = self
+			.connections
+			.iter_mut()
+			.find(|existing| existing.pubkey == connection.pubkey)
+		{
+			connection.name = existing.name.clone();
+			connection.local_port = existing.local_port;
+			connection.mount_point = existing.mount_point.clone();
+			connection.transport = existing.transport.clone();
+			*existing = connection;
+			return Ok(());
+		}
+		if connection.local_port.is_none() {
+			let port = (self.general.port..=u16::MAX)
+				.find(|port| {
+					!self
+						.connections
+						.iter()
+						.any(|existing| existing.local_port == Some(*port))
+				})
+				.with_context(|| {
+					format!("there's no free port left after {}", self.general.port)
+				})?;
+			connection.local_port = Some(port);
+		}
+		if connection.name.trim().is_empty() {
+			connection.name = connection.hostname.clone();
+		}
+		let base_name = connection.name.clone();
+		let mut suffix = 2;
+		while self
+			.connections
+			.iter()
+			.any(|existing| existing.display_name() == connection.name)
+		{
+			connection.name = format!("{} ({})", base_name, suffix);
+			suffix += 1;
+		}
+		self.connections.push(connection);
+		Ok(())
+	}
+
+	/// Removes the connection at `index`, without moving any other device to a different port.
+	pub fn remove_connection(&mut self, index: usize) -> Result<ConnectionConfig> {
+		self.pin_local_ports()?;
+		Ok(self.connections.remove(index))
+	}
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -46,6 +179,11 @@ pub struct GeneralConfig {
 	pub notifications: bool,
 	#[serde(rename = "mount-point")]
 	pub windows_mount_point: Option<String>,
+	// Compression algorithms to offer the server, in order of preference. Empty offers all of them.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub compression: Vec<String>,
+	#[serde(default = "default_zstd_level")]
+	pub zstd_level: i32,
 }
 
 impl Default for GeneralConfig {
@@ -55,6 +193,8 @@ impl Default for GeneralConfig {
 			log_level: LogLevel::default(),
 			notifications: true,
 			windows_mount_point: None,
+			compression: Vec::new(),
+			zstd_level: default_zstd_level(),
 		}
 	}
 }
@@ -63,19 +203,65 @@ pub const fn default_connection_port() -> u16 {
 	28988
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// How the tunnel to a device is set up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Transport {
+	/// We connect to the device's `ip` and `port`.
+	Tcp,
+	/// The device connects to us on `listen_port`, for networks where we can't reach it.
+	Reverse { listen_port: u16 },
+	/// We connect to the device's `port` over USB, through usbmuxd.
+	/// Without a `udid`, whichever device usbmuxd lists first is used.
+	Usb {
+		#[serde(default)]
+		udid: Option<String>,
+	},
+}
+
+impl Transport {
+	pub fn is_tcp(&self) -> bool {
+		*self == Transport::Tcp
+	}
+}
+
+impl Default for Transport {
+	fn default() -> Self {
+		Transport::Tcp
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ConnectionConfig {
+	#[serde(default)]
+	pub name: String,
 	pub ip: IpAddr,
 	#[serde(default = "default_connection_port")]
 	pub port: u16,
 	pub hostname: String,
 	#[serde(with = "crate::Base64")]
 	pub pubkey: Vec<u8>,
+	pub local_port: Option<u16>,
+	pub mount_point: Option<String>,
+	#[serde(default, skip_serializing_if = "Transport::is_tcp")]
+	pub transport: Transport,
+}
+
+impl ConnectionConfig {
+	/// The name used for this device in the tray and in logs, falling back to its hostname.
+	pub fn display_name(&self) -> &str {
+		if self.name.trim().is_empty() {
+			&self.hostname
+		} else {
+			&self.name
+		}
+	}
 }
 
 impl Default for ConnectionConfig {
 	fn default() -> Self {
 		Self {
+			name: "My iPhone".to_string(),
 			ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
 			port: default_connection_port(),
 			hostname: "My iPhone".to_string(),
@@ -83,6 +269,67 @@ impl Default for ConnectionConfig {
 				1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
 				24, 25, 26, 27, 28, 29, 30, 31, 32,
 			],
+			local_port: None,
+			mount_point: None,
+			transport: Transport::default(),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn device(id: u8) -> ConnectionConfig {
+		ConnectionConfig {
+			hostname: format!("device {}", id),
+			pubkey: vec![id; 32],
+			..ConnectionConfig::default()
+		}
+	}
+
+	#[test]
+	fn ports_survive_unpairing() {
+		let mut config = UserConfig::default();
+		let base = config.general.port;
+		// Written before ports were saved, so these two go by their position.
+		config.connections = vec![device(1), device(2)];
+		config.add_connection(device(3)).unwrap();
+		assert_eq!(
+			config
+				.connections
+				.iter()
+				.map(|connection| connection.local_port)
+				.collect::<Vec<_>>(),
+			[Some(base), Some(base + 1), Some(base + 2)]
+		);
+		assert_eq!(config.mount_point(0).as_deref(), Some("W:"));
+
+		assert_eq!(
+			config.remove_connection(0).unwrap().pubkey,
+			device(1).pubkey
+		);
+		assert_eq!(config.local_port(0).unwrap(), base + 1);
+		assert_eq!(config.local_port(1).unwrap(), base + 2);
+		assert_eq!(config.mount_point(0), None);
+
+		// The freed up port, and the drive letter that goes with it, are handed to the next device.
+		config.add_connection(device(4)).unwrap();
+		assert_eq!(config.local_port(2).unwrap(), base);
+		assert_eq!(config.mount_point(2).as_deref(), Some("W:"));
+
+		// Pairing again keeps the port the device already had.
+		config.add_connection(device(2)).unwrap();
+		assert_eq!(config.local_port(0).unwrap(), base + 1);
+	}
+
+	#[test]
+	fn ports_never_wrap() {
+		let mut config = UserConfig::default();
+		config.general.port = u16::MAX;
+		config.connections = vec![device(1), device(2)];
+		assert_eq!(config.local_port(0).unwrap(), u16::MAX);
+		assert!(config.local_port(1).is_err());
+		assert!(config.add_connection(device(3)).is_err());
+	}
+}
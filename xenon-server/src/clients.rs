@@ -0,0 +1,74 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::paths;
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use tokio::sync::RwLock;
+use xenon_config::AuthorizedClient;
+
+pub static AUTHORIZED_CLIENTS: Lazy<RwLock<Vec<AuthorizedClient>>> = Lazy::new(|| {
+	let path = paths::config_dir().join("clients.json");
+	match std::fs::read(&path)
+		.ok()
+		.and_then(|v| serde_json::from_slice::<Vec<AuthorizedClient>>(&v).ok())
+	{
+		Some(clients) => RwLock::new(clients),
+		None => {
+			warn!("no authorized clients found, a client must be paired before it can connect");
+			RwLock::new(Vec::new())
+		}
+	}
+});
+
+async fn save_clients(clients: &[AuthorizedClient]) -> Result<()> {
+	let path = paths::config_dir();
+	if !path.is_dir() {
+		tokio::fs::create_dir_all(&path)
+			.await
+			.context("failed to create storage directory")?;
+	}
+	tokio::fs::write(
+		path.join("clients.json"),
+		serde_json::to_string(clients).context("failed to serialize authorized clients")?,
+	)
+	.await
+	.context("failed to save authorized clients")
+}
+
+pub async fn is_authorized(pubkey: &[u8]) -> bool {
+	AUTHORIZED_CLIENTS
+		.read()
+		.await
+		.iter()
+		.any(|client| client.pubkey == pubkey)
+}
+
+pub async fn authorize(client: AuthorizedClient) -> Result<()> {
+	let mut clients = AUTHORIZED_CLIENTS.write().await;
+	clients.retain(|existing| existing.pubkey != client.pubkey);
+	info!(
+		"authorizing client {} at {}",
+		base64::encode_config(&client.pubkey, base64::URL_SAFE_NO_PAD),
+		client.ip
+	);
+	clients.push(client);
+	save_clients(&clients).await
+}
+
+/// Removes a client from the list of authorized clients, returning whether it was there at all.
+pub async fn revoke(pubkey: &[u8]) -> Result<bool> {
+	let mut clients = AUTHORIZED_CLIENTS.write().await;
+	let len = clients.len();
+	clients.retain(|existing| existing.pubkey != pubkey);
+	if clients.len() == len {
+		return Ok(false);
+	}
+	info!(
+		"revoked client {}",
+		base64::encode_config(pubkey, base64::URL_SAFE_NO_PAD)
+	);
+	save_clients(&clients).await.map(|_| true)
+}
@@ -0,0 +1,72 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::paths;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::time::Duration;
+use xenon_tunnel::{handshake::Features, stream::compression};
+
+const fn default_max_clock_skew() -> u64 {
+	30
+}
+
+const fn default_zstd_level() -> i32 {
+	3
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerConfig {
+	// How many seconds a client's clock may be ahead of or behind ours. Zero disables the check.
+	#[serde(default = "default_max_clock_skew")]
+	pub max_clock_skew: u64,
+	// Compression algorithms we accept, in order of preference. Empty accepts all of them.
+	#[serde(default)]
+	pub compression: Vec<String>,
+	#[serde(default = "default_zstd_level")]
+	pub zstd_level: i32,
+	// Clients to dial ourselves ("host:port"), for networks where they can't reach us.
+	#[serde(default)]
+	pub reverse_connect: Vec<String>,
+}
+
+impl ServerConfig {
+	pub fn max_clock_skew(&self) -> Duration {
+		Duration::from_secs(self.max_clock_skew)
+	}
+
+	pub fn features(&self) -> Features {
+		match compression::parse_preference(&self.compression) {
+			Ok(compression) => Features::with_compression(&compression),
+			Err(err) => {
+				error!("invalid compression in config.toml, allowing all: {}", err);
+				Features::default()
+			}
+		}
+	}
+}
+
+impl Default for ServerConfig {
+	fn default() -> Self {
+		Self {
+			max_clock_skew: default_max_clock_skew(),
+			compression: Vec::new(),
+			zstd_level: default_zstd_level(),
+			reverse_connect: Vec::new(),
+		}
+	}
+}
+
+pub static CONFIG: Lazy<ServerConfig> = Lazy::new(|| {
+	let path = paths::config_dir().join("config.toml");
+	match std::fs::read_to_string(&path) {
+		Ok(contents) => toml::from_str(&contents).unwrap_or_else(|err| {
+			error!("config.toml is invalid, using defaults: {}", err);
+			ServerConfig::default()
+		}),
+		Err(_) => ServerConfig::default(),
+	}
+});
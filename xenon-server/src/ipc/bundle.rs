@@ -3,11 +3,12 @@
 	All rights reserved.
 */
 
-use crate::mount::BUNDLES;
+use crate::{mount::BUNDLES, paths};
 use anyhow::{Context, Result};
+use serde_json::Value;
 
-pub async fn get_app_bundles() -> Result<String> {
-	serde_json::to_string(
+pub async fn get_app_bundles() -> Result<Value> {
+	serde_json::to_value(
 		&BUNDLES
 			.keys()
 			.filter(|name| !name.trim().is_empty())
@@ -16,8 +17,8 @@ pub async fn get_app_bundles() -> Result<String> {
 	.context("failed to serialize json")
 }
 
-pub async fn get_icloud_bundles() -> Result<String> {
-	let mut read_dir = tokio::fs::read_dir("/var/mobile/Library/Mobile Documents")
+pub async fn get_icloud_bundles() -> Result<Value> {
+	let mut read_dir = tokio::fs::read_dir(paths::mobile_dir().join("Library/Mobile Documents"))
 		.await
 		.context("failed to read iCloud bundles!")?;
 	let mut bundles = Vec::<String>::new();
@@ -33,5 +34,5 @@ pub async fn get_icloud_bundles() -> Result<String> {
 		}
 	}
 
-	serde_json::to_string(&bundles).context("failed to serialize json")
+	serde_json::to_value(&bundles).context("failed to serialize json")
 }
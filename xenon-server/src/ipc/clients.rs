@@ -0,0 +1,25 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::clients::{self, AUTHORIZED_CLIENTS};
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+pub async fn list_clients() -> Result<Value> {
+	serde_json::to_value(&*AUTHORIZED_CLIENTS.read().await).context("failed to serialize json")
+}
+
+pub async fn revoke_client(pubkey: &str) -> Result<Value> {
+	let pubkey = base64::decode_config(pubkey.trim(), base64::URL_SAFE_NO_PAD)
+		.context("failed to decode public key")?;
+	if clients::revoke(&pubkey)
+		.await
+		.context("failed to revoke client")?
+	{
+		Ok(Value::from("ok"))
+	} else {
+		anyhow::bail!("no such client")
+	}
+}
@@ -3,17 +3,17 @@
 	All rights reserved.
 */
 
-use crate::{keys::KEYPAIR, CFG_FOLDER};
+use crate::{keys::KEYPAIR, paths};
 use anyhow::{Context, Result};
+use serde_json::Value;
 use snow::Builder;
-use std::path::PathBuf;
-use xenon_config::{get_local_ip, ConnectionConfig, KeypairHelper};
+use xenon_config::{get_local_ip, ConnectionConfig, KeypairHelper, Transport};
 use xenon_tunnel::{NOISE_PARAMS, XENON_PORT};
 
-pub async fn regen_keys() -> Result<String> {
-	let path = PathBuf::from(CFG_FOLDER);
+pub async fn regen_keys() -> Result<Value> {
+	let path = paths::config_dir();
 	if !path.is_dir() {
-		std::fs::create_dir_all(&path).context("failed to create storage directory")?;
+		std::fs::create_dir_all(path).context("failed to create storage directory")?;
 	}
 	let path = path.join("xenon.key");
 	if path.exists() {
@@ -34,10 +34,13 @@ pub async fn regen_keys() -> Result<String> {
 	let pubkey = base64::encode_config(&keypair.public, base64::URL_SAFE_NO_PAD);
 	info!("regenerated keys, new public key is {}", pubkey);
 	*KEYPAIR.write().await = keypair;
-	Ok(pubkey)
+	if let Err(err) = crate::mdns::advertise().await {
+		warn!("failed to update mdns advertisement: {:?}", err);
+	}
+	Ok(Value::from(pubkey))
 }
 
-pub async fn generate_config() -> Result<String> {
+pub async fn generate_config() -> Result<Value> {
 	let ip = get_local_ip().context("failed to get own ip address")?;
 	let hostname = hostname::get()
 		.context("failed to get hostname")
@@ -49,18 +52,24 @@ pub async fn generate_config() -> Result<String> {
 		})?;
 	let pubkey = KEYPAIR.read().await.public.clone();
 	let config = ConnectionConfig {
+		name: hostname.clone(),
 		ip,
 		port: XENON_PORT,
 		hostname,
 		pubkey,
+		local_port: None,
+		mount_point: None,
+		transport: Transport::default(),
 	};
-	toml::to_string_pretty(&config).context("failed to encode new config as toml")
+	toml::to_string_pretty(&config)
+		.map(Value::from)
+		.context("failed to encode new config as toml")
 }
 
-pub async fn get_pubkey() -> Result<String> {
+pub async fn get_pubkey() -> Result<Value> {
 	let keypair = KEYPAIR.read().await;
-	Ok(base64::encode_config(
+	Ok(Value::from(base64::encode_config(
 		&keypair.public,
 		base64::URL_SAFE_NO_PAD,
-	))
+	)))
 }
@@ -4,79 +4,153 @@
 */
 
 mod bundle;
+mod clients;
 mod keys;
 mod qr;
 mod reload;
+mod status;
 
+use crate::paths;
 use anyhow::{Context, Result};
-use std::{ffi::CString, path::PathBuf};
+use async_anyhow_logger::catch_context;
+use serde_json::Value;
+use std::ffi::CString;
 use tokio::{
-	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-	net::UnixListener,
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
 };
+use xenon_config::{
+	decode_ipc_message, encode_ipc_message, ipc_message_len, IpcCommand, IpcRequest, IpcResponse,
+	IPC_HEADER_LEN, IPC_MAGIC, IPC_VERSION,
+};
+
+async fn handle(command: &IpcCommand) -> Result<Value> {
+	match command {
+		IpcCommand::Status => status::get_status()
+			.await
+			.context("failed to get server status"),
+		IpcCommand::ListMounts => status::list_mounts().await.context("failed to list mounts"),
+		IpcCommand::ReloadMounts => reload::reload_mounts()
+			.await
+			.context("failed to reload mounts"),
+		IpcCommand::RegenerateKeys => keys::regen_keys().await.context("failed to generate keys"),
+		IpcCommand::Pubkey => keys::get_pubkey()
+			.await
+			.context("failed to fetch public key"),
+		IpcCommand::Bundles => bundle::get_app_bundles()
+			.await
+			.context("failed to list app bundles"),
+		IpcCommand::IcloudBundles => bundle::get_icloud_bundles()
+			.await
+			.context("failed to list icloud bundles"),
+		IpcCommand::GenerateConfig => keys::generate_config()
+			.await
+			.context("failed to generate configuration string"),
+		IpcCommand::ListClients => clients::list_clients()
+			.await
+			.context("failed to list authorized clients"),
+		IpcCommand::RevokeClient { pubkey } => clients::revoke_client(pubkey)
+			.await
+			.context("failed to revoke client"),
+		IpcCommand::Pair { qr_code } => qr::pair_with_qr(qr_code)
+			.await
+			.context("failed to process qr code"),
+	}
+}
+
+async fn structured_request(stream: &mut BufReader<UnixStream>) -> IpcResponse {
+	let request = async {
+		let mut full_header = [0u8; IPC_HEADER_LEN];
+		full_header[..IPC_MAGIC.len()].copy_from_slice(IPC_MAGIC);
+		stream
+			.read_exact(&mut full_header[IPC_MAGIC.len()..])
+			.await
+			.context("failed to read request")?;
+		let mut json = vec![0u8; ipc_message_len(&full_header)?];
+		stream
+			.read_exact(&mut json)
+			.await
+			.context("failed to read request")?;
+		decode_ipc_message::<IpcRequest>(&json)
+	};
+	let request = match request.await {
+		Ok(o) => o,
+		Err(err) => return IpcResponse::error(format!("{:#}", err)),
+	};
+	debug!("got command from ipc: {:?}", request.command);
+	if request.version > IPC_VERSION {
+		return IpcResponse::error(format!(
+			"unsupported IPC version {}, the server only supports up to {}",
+			request.version, IPC_VERSION
+		));
+	}
+	match handle(&request.command).await {
+		Ok(result) => IpcResponse::ok(result),
+		Err(err) => {
+			error!("processing IPC command errored: {:?}", err);
+			IpcResponse::error(format!("{:#}", err))
+		}
+	}
+}
+
+// The prefs bundle sends a NUL-terminated string, and expects a bare string back.
+async fn legacy_request(stream: &mut BufReader<UnixStream>, mut buf: Vec<u8>) -> Result<String> {
+	if !buf.contains(&0) {
+		stream
+			.read_until(0, &mut buf)
+			.await
+			.context("failed to read bytes")?;
+	}
+	let end = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+	buf.truncate(end);
+	let string = CString::new(buf)
+		.context("failed to process reply into a string")?
+		.into_string()
+		.context("failed to convert replied string to UTF-8")?;
+	debug!("got string from ipc: {}", string);
+	match handle(&IpcCommand::from_legacy(&string)).await? {
+		Value::String(reply) => Ok(reply),
+		reply => Ok(reply.to_string()),
+	}
+}
+
+async fn connection(stream: UnixStream) -> Result<()> {
+	let mut stream = BufReader::new(stream);
+	// Legacy commands can be shorter than the magic, so stop early if one ends.
+	let mut header = Vec::with_capacity(IPC_MAGIC.len());
+	while header.len() < IPC_MAGIC.len() && header.last() != Some(&0) {
+		header.push(stream.read_u8().await.context("failed to read bytes")?);
+	}
+	if header == IPC_MAGIC {
+		let response = encode_ipc_message(&structured_request(&mut stream).await)?;
+		stream
+			.write_all(&response)
+			.await
+			.context("IPC response errored")?;
+	} else {
+		// Errors just close the connection, as that's all the prefs bundle knows how to handle.
+		let reply = legacy_request(&mut stream, header).await?;
+		stream
+			.write_all(reply.as_bytes())
+			.await
+			.context("IPC response errored")?;
+	}
+	stream.shutdown().await.context("IPC response errored")
+}
 
 pub async fn unix_server() -> Result<()> {
-	let path = PathBuf::from("/tmp/me.aspenuwu.xenon.sock");
+	let path = paths::ipc_socket();
 	if path.exists() {
-		tokio::fs::remove_file(&path)
+		tokio::fs::remove_file(path)
 			.await
 			.context("failed to delete previous socket! is the server still running?")?;
 	}
 	let socket = UnixListener::bind(path).context("failed to bind to unix socket")?;
-	let mut buf = Vec::<u8>::with_capacity(64);
-	while let Ok((mut stream, _addr)) = socket.accept().await {
-		buf.clear();
-		let (read, mut write) = stream.split();
-		let mut stream = BufReader::new(read);
-		let string = match stream
-			.read_until(0, &mut buf)
-			.await
-			.context("failed to read bytes")
-			.map(|bytes| &buf[..bytes - 1])
-			.and_then(|bytes| CString::new(bytes).context("failed to process reply into a string"))
-			.and_then(|cstr| {
-				cstr.into_string()
-					.context("failed to convert replied string to UTF-8")
-			}) {
-			Ok(s) => s,
-			Err(e) => {
-				error!("processing IPC reply errored: {}", e);
-				continue;
-			}
-		};
-		debug!("got string from ipc: {}", string);
-		let reply = match string.as_str() {
-			"reload-mounts" => reload::reload_mounts()
-				.await
-				.context("failed to reload mounts"),
-			"regenerate-keys" => keys::regen_keys().await.context("failed to generate keys"),
-			"pubkey" => keys::get_pubkey()
-				.await
-				.context("failed to fetch public key"),
-			"bundles" => bundle::get_app_bundles()
-				.await
-				.context("failed to list app bundles"),
-			"icloud-bundles" => bundle::get_icloud_bundles()
-				.await
-				.context("failed to list icloud bundles"),
-			"generate-config" => keys::generate_config()
-				.await
-				.context("failed to generate configuration string"),
-			_ => qr::pair_with_qr(string)
-				.await
-				.context("failed to process qr code"),
-		};
-		let reply = match reply {
-			Ok(o) => o,
-			Err(e) => {
-				error!("processing IPC reply errored: {}", e);
-				continue;
-			}
-		};
-		if let Err(err) = write.write_all(reply.as_bytes()).await {
-			error!("IPC response errored: {}", err);
-			continue;
-		}
+	while let Ok((stream, _addr)) = socket.accept().await {
+		tokio::spawn(catch_context(
+			"processing IPC request errored",
+			connection(stream),
+		));
 	}
 	Ok(())
 }
@@ -3,11 +3,13 @@
 	All rights reserved.
 */
 
-use crate::keys::KEYPAIR;
+use crate::{clients, keys::KEYPAIR};
 use anyhow::{Context, Result};
 use async_anyhow_logger::catch_context;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::UdpSocket;
-use xenon_config::QrConnection;
+use xenon_config::{AuthorizedClient, QrConnection};
 use xenon_tunnel::XENON_PORT;
 
 async fn qr_connect(qr: QrConnection) -> Result<()> {
@@ -39,11 +41,24 @@ async fn qr_connect(qr: QrConnection) -> Result<()> {
 	Ok(())
 }
 
-pub async fn pair_with_qr(qr: String) -> Result<String> {
+pub async fn pair_with_qr(qr: &str) -> Result<Value> {
 	let qr = QrConnection::from_base64(qr.strip_prefix("XE42~").context("invalid qr code")?)?;
+	if qr.pubkey.len() != 32 {
+		anyhow::bail!("qr code has no client public key, the client needs to be updated");
+	}
+	clients::authorize(AuthorizedClient {
+		pubkey: qr.pubkey.clone(),
+		ip: qr.ip,
+		paired_at: SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.context("system clock is before the UNIX epoch")?
+			.as_secs(),
+	})
+	.await
+	.context("failed to authorize client")?;
 	tokio::spawn(catch_context(
 		"qr pairing connection errored",
 		qr_connect(qr),
 	));
-	Ok("ok".to_string())
+	Ok(Value::from("ok"))
 }
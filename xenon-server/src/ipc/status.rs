@@ -0,0 +1,33 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::{mount::DAV_MOUNTS, stats};
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use xenon_config::MountConfig;
+
+pub async fn get_status() -> Result<Value> {
+	serde_json::to_value(&stats::server_status().await).context("failed to serialize json")
+}
+
+/// Lists the mounts that are actually being served, in the same format as mounts.json.
+pub async fn list_mounts() -> Result<Value> {
+	let mounts = DAV_MOUNTS
+		.read()
+		.await
+		.iter()
+		.map(|(name, mount)| {
+			(
+				name.clone(),
+				MountConfig {
+					mount: mount.source.clone(),
+					permissions: mount.permissions.clone(),
+				},
+			)
+		})
+		.collect::<BTreeMap<_, _>>();
+	serde_json::to_value(&mounts).context("failed to serialize json")
+}
@@ -3,12 +3,17 @@
 	All rights reserved.
 */
 
+#[cfg(target_os = "ios")]
 use std::ffi::CStr;
+#[cfg(target_os = "ios")]
 use std::os::raw::{c_char, c_void};
 
+#[cfg(target_os = "ios")]
 const MEMORYSTATUS_CMD_SET_JETSAM_TASK_LIMIT: u32 = 6;
+#[cfg(target_os = "ios")]
 const XENON_MEMORY_LIMIT_MB: u32 = 100;
 
+#[cfg(target_os = "ios")]
 extern "C" {
 	fn memorystatus_control(
 		command: u32,
@@ -21,6 +26,7 @@ extern "C" {
 	fn strerror(err: i32) -> *const c_char;
 }
 
+#[cfg(target_os = "ios")]
 pub fn there_will_be_blood_yeaahh() {
 	let ret = unsafe {
 		memorystatus_control(
@@ -46,3 +52,9 @@ pub fn there_will_be_blood_yeaahh() {
 		XENON_MEMORY_LIMIT_MB
 	)
 }
+
+// Jetsam only exists on iOS, everywhere else there's nothing to limit.
+#[cfg(not(target_os = "ios"))]
+pub fn there_will_be_blood_yeaahh() {
+	debug!("not on iOS, leaving memory limits alone");
+}
@@ -3,18 +3,17 @@
 	All rights reserved.
 */
 
-use crate::CFG_FOLDER;
+use crate::paths;
 use once_cell::sync::Lazy;
 use snow::{Builder, Keypair};
-use std::path::PathBuf;
 use tokio::sync::RwLock;
 use xenon_config::KeypairHelper;
 use xenon_tunnel::NOISE_PARAMS;
 
 pub static KEYPAIR: Lazy<RwLock<Keypair>> = Lazy::new(|| {
-	let path = PathBuf::from(CFG_FOLDER);
+	let path = paths::config_dir();
 	if !path.is_dir() {
-		std::fs::create_dir_all(&path).expect("failed to create storage directory");
+		std::fs::create_dir_all(path).expect("failed to create storage directory");
 	}
 	let path = path.join("xenon.key");
 	match std::fs::read(&path)
@@ -0,0 +1,30 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+#![deny(clippy::complexity, clippy::correctness, clippy::perf, clippy::style)]
+
+#[macro_use]
+extern crate log;
+
+pub mod clients;
+pub mod config;
+pub mod ipc;
+pub mod jetsam;
+pub mod keys;
+pub mod logger;
+pub mod mdns;
+pub mod mount;
+pub mod paths;
+pub mod server;
+pub mod shutdown;
+pub mod stats;
+
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use webdav_handler::DavHandler;
+
+pub use paths::CFG_FOLDER;
+
+pub static DAV_HANDLERS: OnceCell<HashMap<String, DavHandler>> = OnceCell::new();
@@ -3,22 +3,31 @@
 	All rights reserved.
 */
 
-use crate::CFG_FOLDER;
-use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::paths;
+use log::{Level, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+#[cfg(any(target_os = "ios", target_os = "macos"))]
 use oslog::OsLogger;
-use std::path::PathBuf;
 use tokio::{
 	fs::File,
 	io::AsyncWriteExt,
-	sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+	sync::{
+		mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+		oneshot,
+	},
 };
 
+// Kept around so the file can be closed on shutdown, as the logger itself is owned by the log crate.
+static LOG_TX: OnceCell<UnboundedSender<LogMessage>> = OnceCell::new();
+
 pub enum LogMessage {
 	Message(String),
 	Flush,
+	Close(oneshot::Sender<()>),
 }
 
 pub struct XenonLogger {
+	#[cfg(any(target_os = "ios", target_os = "macos"))]
 	os: OsLogger,
 	tx: UnboundedSender<LogMessage>,
 }
@@ -26,9 +35,11 @@ pub struct XenonLogger {
 impl XenonLogger {
 	pub fn new() -> (Self, UnboundedReceiver<LogMessage>) {
 		let (tx, rx) = unbounded_channel();
+		let _ = LOG_TX.set(tx.clone());
 		(
 			XenonLogger {
-				os: OsLogger::new("me.aspenuwu.xenon").level_filter(LevelFilter::Info),
+				#[cfg(any(target_os = "ios", target_os = "macos"))]
+				os: OsLogger::new("me.aspenuwu.xenon").level_filter(log::LevelFilter::Info),
 				tx,
 			},
 			rx,
@@ -48,6 +59,7 @@ impl Log for XenonLogger {
 	}
 
 	fn log(&self, record: &Record) {
+		#[cfg(any(target_os = "ios", target_os = "macos"))]
 		self.os.log(record);
 		let _ = self.tx.send(LogMessage::Message(format!(
 			"[{}] {}\n",
@@ -62,9 +74,9 @@ impl Log for XenonLogger {
 }
 
 pub async fn file_logging_task(mut rx: UnboundedReceiver<LogMessage>) {
-	let cfg_dir = PathBuf::from(CFG_FOLDER);
+	let cfg_dir = paths::config_dir();
 	if !cfg_dir.is_dir() {
-		let _ = tokio::fs::create_dir_all(&cfg_dir).await;
+		let _ = tokio::fs::create_dir_all(cfg_dir).await;
 	}
 	let mut log_file = File::create(cfg_dir.join("daemon.log"))
 		.await
@@ -77,6 +89,23 @@ pub async fn file_logging_task(mut rx: UnboundedReceiver<LogMessage>) {
 			LogMessage::Flush => {
 				let _ = log_file.flush().await;
 			}
+			LogMessage::Close(done) => {
+				let _ = log_file.flush().await;
+				let _ = done.send(());
+				return;
+			}
 		}
 	}
 }
+
+/// Writes out everything logged so far to daemon.log. Anything logged afterwards only goes to the system log.
+pub async fn close() {
+	let tx = match LOG_TX.get() {
+		Some(tx) => tx,
+		None => return,
+	};
+	let (done_tx, done_rx) = oneshot::channel();
+	if tx.send(LogMessage::Close(done_tx)).is_ok() {
+		let _ = done_rx.await;
+	}
+}
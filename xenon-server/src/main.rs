@@ -8,28 +8,51 @@
 #[macro_use]
 extern crate log;
 
-pub mod ipc;
-pub mod jetsam;
-pub mod keys;
-pub mod logger;
-pub mod mount;
-pub mod server;
-
 use anyhow::{Context, Result};
 use async_anyhow_logger::catch_context;
-use once_cell::sync::OnceCell;
-use std::{collections::HashMap, path::PathBuf};
-use std::{io::Write, ops::Deref};
-use webdav_handler::DavHandler;
-use xenon_config::MountType;
+use std::{io::Write, ops::Deref, path::PathBuf, time::Duration};
+use structopt::StructOpt;
+use xenon_server::{
+	clients, ipc, jetsam, keys, mount,
+	paths::{self, Paths},
+	server, shutdown,
+};
+
+// launchd kills us outright if we take much longer than this.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything defaults to where it lives on iOS, but can be moved elsewhere to run the daemon off-device.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "xenon-server")]
+struct Opt {
+	/// Where keys, config.toml, clients.json, mounts.json and logs are kept.
+	#[structopt(long, parse(from_os_str))]
+	config_dir: Option<PathBuf>,
+	/// Stands in for /var/mobile, which holds app containers, photos and iCloud Drive.
+	#[structopt(long, parse(from_os_str))]
+	mobile_dir: Option<PathBuf>,
+	/// The unix socket the preference bundle talks to us over.
+	#[structopt(long, parse(from_os_str))]
+	ipc_socket: Option<PathBuf>,
+}
 
-pub static DAV_HANDLERS: OnceCell<HashMap<String, DavHandler>> = OnceCell::new();
-pub const CFG_FOLDER: &str = "/var/mobile/Library/me.aspenuwu.xenon";
+impl Opt {
+	fn paths(self) -> Paths {
+		let defaults = Paths::default();
+		Paths {
+			config: self.config_dir.unwrap_or(defaults.config),
+			mobile: self.mobile_dir.unwrap_or(defaults.mobile),
+			ipc_socket: self.ipc_socket.unwrap_or(defaults.ipc_socket),
+		}
+	}
+}
 
 #[cfg(not(debug_assertions))]
 async fn init_logging() -> Result<()> {
-	let (logger, rx) = logger::XenonLogger::new();
-	let _ = tokio::spawn(logger::file_logging_task(rx));
+	use xenon_server::logger::{file_logging_task, XenonLogger};
+
+	let (logger, rx) = XenonLogger::new();
+	let _ = tokio::spawn(file_logging_task(rx));
 	log::set_boxed_logger(Box::new(logger)).context("failed to set up logger")
 }
 
@@ -65,7 +88,7 @@ fn log_panics() {
 			}
 		};
 		error!(target: "panic", "{}", error_text);
-		let crash_log = PathBuf::from(CFG_FOLDER).join("crash.log");
+		let crash_log = paths::config_dir().join("crash.log");
 		let file = std::fs::File::create(&crash_log);
 		match file {
 			Ok(mut file) => {
@@ -86,13 +109,15 @@ fn log_panics() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+	// Nothing has looked at the paths yet, so this can't fail.
+	paths::set(Opt::from_args().paths());
 	init_logging().await?;
 	log_panics();
 	jetsam::there_will_be_blood_yeaahh();
 
-	let cfg_dir = PathBuf::from(CFG_FOLDER);
+	let cfg_dir = paths::config_dir();
 	if !cfg_dir.is_dir() {
-		if let Err(err) = tokio::fs::create_dir_all(&cfg_dir).await {
+		if let Err(err) = tokio::fs::create_dir_all(cfg_dir).await {
 			error!(
 				"failed to create config directory at {}: {:?}",
 				cfg_dir.display(),
@@ -101,31 +126,35 @@ async fn main() -> Result<()> {
 		}
 	}
 
-	// Initialize this lazy value early.
+	// Initialize these lazy values early.
 	let _ = keys::KEYPAIR.deref();
+	let _ = clients::AUTHORIZED_CLIENTS.deref();
 
 	tokio::spawn(catch_context("unix socket IPC errored", ipc::unix_server()));
 
-	match tokio::fs::read_to_string(PathBuf::from(CFG_FOLDER).join("mounts.json"))
-		.await
-		.and_then(|contents| {
-			serde_json::from_str::<HashMap<String, MountType>>(&contents)
-				.map_err(std::io::Error::from)
-		}) {
-		Ok(mounts) => {
-			info!("loaded mounts.json");
-			let mut ret = HashMap::<String, DavHandler>::new();
-			for (name, mount) in mounts {
-				if let Some(dav_handler) = mount::create_dav_handler(&name, mount) {
-					ret.insert(name, dav_handler);
-				}
-			}
-			*mount::DAV_MOUNTS.write().await = ret;
-		}
-		Err(e) => {
-			error!("failed to read mounts.json: {}", e);
-		}
-	};
+	if let Err(err) = mount::load_mounts().await {
+		error!("{:?}", err);
+	}
+	tokio::spawn(catch_context(
+		"watching mounts.json errored",
+		mount::watch_mounts(),
+	));
 
-	server::listener().await.context("server errored")
+	tokio::select! {
+		result = server::listener() => result.context("server errored")?,
+		_ = shutdown::signalled() => {}
+	}
+	shutdown::request();
+	if !shutdown::drain(DRAIN_TIMEOUT).await {
+		warn!(
+			"requests were still in flight after {} seconds, exiting anyways",
+			DRAIN_TIMEOUT.as_secs()
+		);
+	}
+	if let Err(err) = tokio::fs::remove_file(paths::ipc_socket()).await {
+		warn!("failed to remove IPC socket: {}", err);
+	}
+	info!("shut down");
+	xenon_server::logger::close().await;
+	Ok(())
 }
@@ -0,0 +1,57 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::keys::KEYPAIR;
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use xenon_config::get_local_ip;
+use xenon_tunnel::{MDNS_SERVICE_TYPE, XENON_PORT};
+
+static MDNS: OnceCell<ServiceDaemon> = OnceCell::new();
+
+/// Advertises this server over mDNS, so clients can find it even when its IP address changes.
+/// Calling this again re-registers the service, which is needed after the keys are regenerated.
+pub async fn advertise() -> Result<()> {
+	let daemon = match MDNS.get() {
+		Some(daemon) => daemon,
+		None => {
+			let daemon = ServiceDaemon::new().context("failed to start mdns daemon")?;
+			MDNS.get_or_init(|| daemon)
+		}
+	};
+	let hostname = hostname::get()
+		.context("failed to get hostname")
+		.and_then(|hostname| {
+			hostname
+				.to_str()
+				.map(|hostname| hostname.to_string())
+				.context("failed to convert hostname to string")
+		})?;
+	let ip = get_local_ip().context("failed to get own ip address")?;
+	let pubkey = base64::encode_config(&KEYPAIR.read().await.public, base64::URL_SAFE_NO_PAD);
+	let mut properties = HashMap::<String, String>::new();
+	properties.insert("pubkey".to_string(), pubkey);
+	properties.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+	let service = ServiceInfo::new(
+		MDNS_SERVICE_TYPE,
+		&hostname,
+		&format!("{}.local.", hostname),
+		ip,
+		XENON_PORT,
+		properties,
+	)
+	.context("failed to build mdns service info")?
+	.enable_addr_auto();
+	daemon
+		.register(service)
+		.context("failed to register mdns service")?;
+	info!(
+		"advertising '{}' as {} over mdns",
+		hostname, MDNS_SERVICE_TYPE
+	);
+	Ok(())
+}
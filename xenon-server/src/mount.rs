@@ -3,41 +3,75 @@
 	All rights reserved.
 */
 
-use crate::server::photofs::PhotoFs;
+use crate::{paths, server::photofs::PhotoFs};
+use anyhow::{Context, Result};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, path::PathBuf};
-use tokio::sync::RwLock;
+use std::{
+	collections::{BTreeMap, HashMap},
+	ffi::OsStr,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+use tokio::sync::{mpsc::unbounded_channel, RwLock};
 use webdav_handler::{localfs::LocalFs, memls::MemLs, DavHandler};
-use xenon_config::{MountPreset, MountType};
+use xenon_config::{
+	MountAccess, MountConfig, MountError, MountPermissions, MountPreset, MountStatus, MountType,
+};
 
-pub static DAV_MOUNTS: Lazy<RwLock<HashMap<String, DavHandler>>> =
-	Lazy::new(|| RwLock::new(HashMap::new()));
+const MOUNTS_FILE: &str = "mounts.json";
+// Editors and the prefs bundle can write the file in several steps, so wait for it to settle.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(1);
 
-pub static BUNDLES: Lazy<HashMap<String, PathBuf>> = Lazy::new(|| {
-	let mut out = HashMap::<String, PathBuf>::new();
+pub struct Mount {
+	pub handler: DavHandler,
+	// What this was created from, so it can be reported back over IPC.
+	pub source: MountType,
+	pub permissions: MountPermissions,
+}
 
-	for entry in std::fs::read_dir("/var/mobile/Containers/Shared/AppGroup")
-		.expect("failed to read AppGroup container")
-	{
-		if let Ok(entry) = entry {
-			let path = entry.path();
-			if let Some(name) = get_name_from_plist(&path) {
-				out.insert(name, path);
-			}
-		}
+impl Mount {
+	pub fn access_for(&self, client: &[u8]) -> MountAccess {
+		self.permissions.access_for(client)
 	}
+}
 
-	for entry in std::fs::read_dir("/var/mobile/Containers/Data/Application")
-		.expect("failed to read Applications container")
-	{
-		if let Ok(entry) = entry {
-			let path = entry.path();
-			if let Some(name) = get_name_from_plist(&path) {
-				out.insert(name, path);
-			}
+pub static DAV_MOUNTS: Lazy<RwLock<HashMap<String, Mount>>> =
+	Lazy::new(|| RwLock::new(HashMap::new()));
+
+// How each mount in mounts.json fared the last time it was loaded, including the ones that aren't being served.
+// Their permissions are kept alongside, so clients are only told about the mounts they can see.
+pub static MOUNT_STATUS: Lazy<RwLock<BTreeMap<String, (MountPermissions, MountStatus)>>> =
+	Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+// Fixture trees used off-device may not have every container, so a missing one just has no bundles.
+fn read_containers(dir: PathBuf, out: &mut HashMap<String, PathBuf>) {
+	let entries = match std::fs::read_dir(&dir) {
+		Ok(o) => o,
+		Err(err) => {
+			warn!("failed to read containers in {}: {}", dir.display(), err);
+			return;
+		}
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if let Some(name) = get_name_from_plist(&path) {
+			out.insert(name, path);
 		}
 	}
+}
 
+pub static BUNDLES: Lazy<HashMap<String, PathBuf>> = Lazy::new(|| {
+	let mut out = HashMap::<String, PathBuf>::new();
+	read_containers(
+		paths::mobile_dir().join("Containers/Shared/AppGroup"),
+		&mut out,
+	);
+	read_containers(
+		paths::mobile_dir().join("Containers/Data/Application"),
+		&mut out,
+	);
 	out
 });
 
@@ -49,62 +83,161 @@ pub fn get_name_from_plist(dir: &PathBuf) -> Option<String> {
 		.and_then(|x| plist::Value::into_string(x.clone()))
 }
 
-pub fn create_dav_handler(name: &str, mount: MountType) -> Option<DavHandler> {
+// Reading the directory catches permission problems, which `is_dir` would mistake for it not existing.
+fn check_dir(path: &Path) -> Result<(), MountError> {
+	match std::fs::read_dir(path) {
+		Ok(_) => Ok(()),
+		Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+			Err(MountError::PermissionDenied {
+				path: path.to_path_buf(),
+			})
+		}
+		Err(_) => Err(MountError::PathMissing {
+			path: path.to_path_buf(),
+		}),
+	}
+}
+
+fn find_bundle(bundle: &str) -> Result<&'static PathBuf, MountError> {
+	BUNDLES
+		.get(bundle)
+		.ok_or_else(|| MountError::BundleNotFound {
+			bundle: bundle.to_string(),
+		})
+}
+
+pub fn create_dav_handler(name: &str, mount: MountType) -> Result<DavHandler, MountError> {
 	if !name
 		.chars()
 		.all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_')
 		|| name.eq_ignore_ascii_case("xenon")
+		|| name.eq_ignore_ascii_case(crate::server::RESERVED_MOUNT)
 	{
-		warn!("Mount [{}] has an invalid name, skipping!", name);
-		return None;
+		return Err(MountError::InvalidName);
 	}
 	match mount {
 		MountType::Bundle(bundle) => {
-			create_dav_handler(name, MountType::Path(BUNDLES.get(&bundle)?.to_owned()))
+			create_dav_handler(name, MountType::Path(find_bundle(&bundle)?.to_owned()))
 		}
 		MountType::ICloudBundle(bundle) => {
-			let bundle_path = PathBuf::from("/var/mobile/Library/Mobile Documents")
+			let bundle_path = paths::mobile_dir()
+				.join("Library/Mobile Documents")
 				.join(bundle.replace('.', "~"));
 			create_dav_handler(name, MountType::Path(bundle_path))
 		}
 		MountType::Path(path) => {
-			if path.is_dir() {
-				info!("Mount '{}' -> {}", name, path.display());
-				Some(
-					DavHandler::builder()
-						.locksystem(MemLs::new())
-						.filesystem(LocalFs::new(path, true, false, true))
-						.strip_prefix(["/", name].join(""))
-						.build_handler(),
-				)
-			} else {
-				error!("Mount '{}' -> {} DOESN'T EXIST", name, path.display());
-				None
-			}
+			check_dir(&path)?;
+			info!("Mount '{}' -> {}", name, path.display());
+			Ok(DavHandler::builder()
+				.locksystem(MemLs::new())
+				.filesystem(LocalFs::new(path, true, false, true))
+				.strip_prefix(["/", name].join(""))
+				.build_handler())
 		}
 		MountType::Preset(preset) => match preset {
 			MountPreset::Photos => {
 				info!("Mount '{}' -> Photos", name);
-				Some(
-					DavHandler::builder()
-						.locksystem(MemLs::new())
-						.filesystem(Box::new(PhotoFs::default()))
-						.strip_prefix(["/", name].join(""))
-						.build_handler(),
-				)
+				Ok(DavHandler::builder()
+					.locksystem(MemLs::new())
+					.filesystem(Box::new(PhotoFs::default()))
+					.strip_prefix(["/", name].join(""))
+					.build_handler())
 			}
 			MountPreset::LocalFiles => create_dav_handler(
 				name,
 				MountType::Path(
-					BUNDLES
-						.get("group.com.apple.FileProvider.LocalStorage")?
+					find_bundle("group.com.apple.FileProvider.LocalStorage")?
 						.join("File Provider Storage"),
 				),
 			),
-			MountPreset::Home => create_dav_handler(name, MountType::Path("/var/mobile".into())),
+			MountPreset::Home => {
+				create_dav_handler(name, MountType::Path(paths::mobile_dir().into()))
+			}
 			MountPreset::Documents => {
-				create_dav_handler(name, MountType::Path("/var/mobile/Documents".into()))
+				create_dav_handler(name, MountType::Path(paths::mobile_dir().join("Documents")))
 			}
 		},
 	}
 }
+
+/// Reads mounts.json and swaps in the mounts it describes, returning how loading each of them went.
+/// If it can't be read or parsed, the mounts that are already being served are kept.
+pub async fn load_mounts() -> Result<BTreeMap<String, MountStatus>> {
+	let mounts: HashMap<String, MountConfig> =
+		tokio::fs::read_to_string(paths::config_dir().join(MOUNTS_FILE))
+			.await
+			.and_then(|contents| serde_json::from_str(&contents).map_err(std::io::Error::from))
+			.context("failed to read mounts.json")?;
+	let mut ret = HashMap::<String, Mount>::new();
+	let mut status = BTreeMap::<String, (MountPermissions, MountStatus)>::new();
+	for (name, mount) in mounts {
+		let handler = create_dav_handler(&name, mount.mount.clone());
+		status.insert(
+			name.clone(),
+			(mount.permissions.clone(), MountStatus::from(&handler)),
+		);
+		match handler {
+			Ok(handler) => {
+				ret.insert(
+					name,
+					Mount {
+						handler,
+						source: mount.mount,
+						permissions: mount.permissions,
+					},
+				);
+			}
+			Err(err) => warn!("Mount '{}' is being skipped: {}", name, err),
+		}
+	}
+	*DAV_MOUNTS.write().await = ret;
+	let ret = status
+		.iter()
+		.map(|(name, (_, status))| (name.clone(), status.clone()))
+		.collect();
+	*MOUNT_STATUS.write().await = status;
+	info!("loaded mounts.json");
+	Ok(ret)
+}
+
+fn is_mounts_file(event: &DebouncedEvent) -> bool {
+	match event {
+		DebouncedEvent::Create(path)
+		| DebouncedEvent::Write(path)
+		| DebouncedEvent::Rename(_, path) => path.file_name() == Some(OsStr::new(MOUNTS_FILE)),
+		_ => false,
+	}
+}
+
+/// Reloads mounts.json whenever it changes.
+pub async fn watch_mounts() -> Result<()> {
+	let (tx, rx) = std::sync::mpsc::channel();
+	// The directory is watched rather than the file, so that it still works when the file is replaced or created.
+	let mut watcher =
+		notify::watcher(tx, WATCH_DEBOUNCE).context("failed to create file watcher")?;
+	watcher
+		.watch(paths::config_dir(), RecursiveMode::NonRecursive)
+		.context("failed to watch config directory")?;
+	let (changed_tx, mut changed_rx) = unbounded_channel();
+	// notify only speaks std channels, so hand its events over from a thread of its own.
+	std::thread::Builder::new()
+		.name("mounts watcher".to_string())
+		.spawn(move || {
+			for event in rx {
+				if is_mounts_file(&event) && changed_tx.send(()).is_err() {
+					break;
+				}
+			}
+		})
+		.context("failed to spawn watcher thread")?;
+	while changed_rx.recv().await.is_some() {
+		info!("mounts.json changed, reloading");
+		if let Err(err) = load_mounts().await {
+			error!(
+				"failed to reload mounts, keeping the current ones: {:?}",
+				err
+			);
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,53 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use once_cell::sync::OnceCell;
+use std::path::{Path, PathBuf};
+
+pub const CFG_FOLDER: &str = "/var/mobile/Library/me.aspenuwu.xenon";
+pub const MOBILE_FOLDER: &str = "/var/mobile";
+pub const IPC_SOCKET: &str = "/tmp/me.aspenuwu.xenon.sock";
+
+/// Where the server keeps its own state, and where it looks for the user's files.
+#[derive(Clone, Debug)]
+pub struct Paths {
+	pub config: PathBuf,
+	// Stands in for /var/mobile, which holds app containers, photos and iCloud Drive.
+	pub mobile: PathBuf,
+	pub ipc_socket: PathBuf,
+}
+
+impl Default for Paths {
+	fn default() -> Self {
+		Self {
+			config: PathBuf::from(CFG_FOLDER),
+			mobile: PathBuf::from(MOBILE_FOLDER),
+			ipc_socket: PathBuf::from(IPC_SOCKET),
+		}
+	}
+}
+
+static PATHS: OnceCell<Paths> = OnceCell::new();
+
+/// Overrides the default paths. Has to happen before anything reads them, so returns false if it's too late.
+pub fn set(paths: Paths) -> bool {
+	PATHS.set(paths).is_ok()
+}
+
+pub fn get() -> &'static Paths {
+	PATHS.get_or_init(Paths::default)
+}
+
+pub fn config_dir() -> &'static Path {
+	&get().config
+}
+
+pub fn mobile_dir() -> &'static Path {
+	&get().mobile
+}
+
+pub fn ipc_socket() -> &'static Path {
+	&get().ipc_socket
+}
@@ -4,8 +4,7 @@
 */
 
 use crate::mount::DAV_MOUNTS;
-use once_cell::sync::Lazy;
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 use webdav_handler::{
 	davpath::DavPath,
 	fs::{
@@ -14,15 +13,21 @@ use webdav_handler::{
 	},
 	DavHandler,
 };
+use xenon_config::MountAccess;
 
-pub static METAFS: Lazy<DavHandler> = Lazy::new(|| {
-	DavHandler::builder()
-		.filesystem(Box::new(MetaFs))
-		.build_handler()
-});
+// Lists the mounts visible to a specific client.
+#[derive(Clone)]
+pub struct MetaFs {
+	client: Arc<Vec<u8>>,
+}
 
-#[derive(Copy, Clone)]
-pub struct MetaFs;
+impl MetaFs {
+	pub fn handler(client: Arc<Vec<u8>>) -> DavHandler {
+		DavHandler::builder()
+			.filesystem(Box::new(MetaFs { client }))
+			.build_handler()
+	}
+}
 
 impl DavFileSystem for MetaFs {
 	fn open<'a>(&'a self, path: &'a DavPath, _: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
@@ -38,9 +43,12 @@ impl DavFileSystem for MetaFs {
 		Box::pin(async move {
 			let mut mounts = Vec::<Box<dyn DavDirEntry>>::new();
 			let global_mounts = DAV_MOUNTS.read().await;
-			for mount in global_mounts.keys() {
+			for (name, mount) in global_mounts.iter() {
+				if mount.access_for(&self.client) == MountAccess::Hidden {
+					continue;
+				}
 				mounts.push(Box::new(MetaFsEntry {
-					name: mount.as_bytes().to_vec(),
+					name: name.as_bytes().to_vec(),
 				}))
 			}
 			Ok(Box::pin(futures::stream::iter(mounts.into_iter()))
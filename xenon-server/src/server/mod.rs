@@ -5,55 +5,205 @@
 
 pub mod metafs;
 pub mod photofs;
+pub mod reverse;
+pub mod routing;
 
-use self::metafs::METAFS;
-use crate::mount::DAV_MOUNTS;
+use self::metafs::MetaFs;
+use crate::{
+	mount::DAV_MOUNTS,
+	shutdown,
+	stats::{self, ConnectionStats, CountedStream},
+};
 use anyhow::{Context, Result};
 use async_anyhow_logger::catch_context;
-use http::Request;
+use http::{Method, Request};
 use hyper::{server::conn::Http, service::service_fn, Body, Response, StatusCode};
-use snow::{Builder, HandshakeState, TransportState};
-use std::{convert::Infallible, net::SocketAddr};
-use tokio::net::{TcpListener, TcpStream};
-use xenon_tunnel::{handshake, net, EncryptedTcpStream, NOISE_PARAMS, XENON_PORT};
+use snow::{Builder, TransportState};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpListener,
+	try_join,
+};
+use xenon_config::MountAccess;
+use xenon_tunnel::{
+	control,
+	handshake::{self, Negotiated, Rejection},
+	mux::{Multiplexer, Role, Service},
+	net,
+	stream::compression,
+	EncryptedStream, NOISE_PARAMS, XENON_PORT,
+};
 
-async fn server(snowfall: TransportState, stream: TcpStream, addr: SocketAddr) -> Result<()> {
-	let stream = EncryptedTcpStream::new(snowfall, stream);
+/// Requests under this are answered by the server itself, so no mount can use it as a name.
+pub const RESERVED_MOUNT: &str = ".xenon";
 
-	Http::new()
+// The only methods read-only mounts allow, anything else might modify the filesystem.
+fn is_read_method(method: &Method) -> bool {
+	matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS" | "PROPFIND")
+}
+
+fn error_response(status: StatusCode, body: &'static str) -> Response<webdav_handler::body::Body> {
+	Response::builder()
+		.status(status)
+		.body(webdav_handler::body::Body::from(body))
+		.expect("failed to build error response")
+}
+
+async fn status_response(
+	client: &[u8],
+	stats: &ConnectionStats,
+) -> Response<webdav_handler::body::Body> {
+	match serde_json::to_string(&stats::client_status(client, stats).await) {
+		Ok(json) => Response::builder()
+			.header(http::header::CONTENT_TYPE, "application/json")
+			.body(webdav_handler::body::Body::from(json))
+			.expect("failed to build status response"),
+		Err(err) => {
+			error!("failed to serialize status: {:?}", err);
+			error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to get status")
+		}
+	}
+}
+
+async fn serve_http<T>(
+	stream: T,
+	addr: SocketAddr,
+	client: Arc<Vec<u8>>,
+	stats: Arc<ConnectionStats>,
+	max_frame_size: u32,
+) -> Result<()>
+where
+	T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let _active = shutdown::track();
+	let connection = Http::new()
 		.http2_only(true)
-		.http2_max_frame_size(xenon_tunnel::MAX_FRAME_SIZE)
+		.http2_max_frame_size(max_frame_size)
 		.serve_connection(
 			stream,
-			service_fn(|req: Request<Body>| async move {
-				let first_part = format!("{} -> {}", addr, req.uri());
-				let path = req.uri().path().trim();
-				let path = path.strip_prefix("/").unwrap_or(path);
-				if path.is_empty() {
-					debug!("{} -> MetaFS", path);
-					return Ok::<_, Infallible>(METAFS.handle(req).await);
-				}
-				let global_mounts = DAV_MOUNTS.read().await;
-				let response = match global_mounts
-					.iter()
-					.find(|(key, _)| path.starts_with(key.as_str()))
-				{
-					Some((name, dav)) => {
-						debug!("{} -> real FS {}", path, name);
-						dav.handle(req).await
+			service_fn(|req: Request<Body>| {
+				let client = client.clone();
+				let stats = stats.clone();
+				async move {
+					let first_part = format!("{} -> {}", addr, req.uri());
+					let path = req.uri().path().trim();
+					let path = path.strip_prefix("/").unwrap_or(path);
+					if path.is_empty() {
+						debug!("{} -> MetaFS", path);
+						return Ok::<_, Infallible>(MetaFs::handler(client).handle(req).await);
+					}
+					if path.split('/').next() == Some(RESERVED_MOUNT) {
+						let response = match path {
+							".xenon/status" if req.method() == Method::GET => {
+								status_response(&client, &stats).await
+							}
+							_ => error_response(StatusCode::NOT_FOUND, "not found"),
+						};
+						debug!("{} -> {}", first_part, response.status());
+						return Ok::<_, Infallible>(response);
 					}
-					None => Response::builder()
-						.status(StatusCode::NOT_FOUND)
-						.body(webdav_handler::body::Body::from("not found"))
-						.expect("failed to send 404"),
-				};
-				debug!("{} -> {}", first_part, response.status());
-				Ok::<_, Infallible>(response)
+					let global_mounts = DAV_MOUNTS.read().await;
+					let response = match routing::find_mount(&global_mounts, path) {
+						Some((name, mount)) => match mount.access_for(&client) {
+							MountAccess::Hidden => {
+								debug!("{} -> {} is hidden from {}", path, name, addr);
+								error_response(StatusCode::NOT_FOUND, "not found")
+							}
+							MountAccess::ReadOnly if !is_read_method(req.method()) => {
+								debug!("{} -> {} is read-only for {}", path, name, addr);
+								error_response(StatusCode::FORBIDDEN, "mount is read-only")
+							}
+							_ => {
+								debug!("{} -> real FS {}", path, name);
+								stats.count_request(name);
+								mount.handler.handle(req).await
+							}
+						},
+						None => error_response(StatusCode::NOT_FOUND, "not found"),
+					};
+					debug!("{} -> {}", first_part, response.status());
+					Ok::<_, Infallible>(response)
+				}
 			}),
-		)
-		.await
-		.context("connection errored")?;
-	Ok(())
+		);
+	tokio::pin!(connection);
+	// On shutdown, hyper sends a GOAWAY and lets requests that are already in flight finish.
+	let result = tokio::select! {
+		result = &mut connection => result,
+		_ = shutdown::requested() => {
+			connection.as_mut().graceful_shutdown();
+			connection.await
+		}
+	}
+	.context("connection errored");
+	if let Err(err) = &result {
+		stats.set_error(err);
+	}
+	result
+}
+
+async fn server<T>(
+	snowfall: TransportState,
+	stream: T,
+	addr: SocketAddr,
+	client: Vec<u8>,
+	stats: Arc<ConnectionStats>,
+	negotiated: Negotiated,
+) -> Result<()>
+where
+	T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let mut stream = EncryptedStream::new(
+		snowfall,
+		CountedStream::new(stream, stats.clone()),
+		compression::from_algorithm(negotiated.compression, crate::config::CONFIG.zstd_level),
+	);
+	stream.set_rekey_policy(negotiated.rekey_policy());
+	let client = Arc::new(client);
+	if !negotiated.multiplex {
+		return serve_http(stream, addr, client, stats, negotiated.max_frame_size).await;
+	}
+
+	// The client opens the streams, we just serve whatever it asks for.
+	let (_mux, mut incoming, driver) = Multiplexer::new(stream, Role::Responder);
+	let accept = async move {
+		while let Some((service, stream)) = incoming.accept().await {
+			trace!("{} -> opened stream {} ({:?})", addr, stream.id(), service);
+			match service {
+				Service::Http => {
+					tokio::spawn(catch_context(
+						"http stream errored",
+						serve_http(
+							stream,
+							addr,
+							client.clone(),
+							stats.clone(),
+							negotiated.max_frame_size,
+						),
+					));
+				}
+				Service::Control => {
+					tokio::spawn(catch_context(
+						"control stream errored",
+						control::respond(stream),
+					));
+				}
+				Service::Other(service) => {
+					debug!(
+						"{} -> ignoring stream for unknown service {}",
+						addr, service
+					);
+				}
+			}
+		}
+		Ok::<_, anyhow::Error>(())
+	};
+	try_join!(
+		async move { driver.await.context("tunnel errored") },
+		accept
+	)
+	.map(|_| ())
 }
 
 pub async fn listener() -> Result<()> {
@@ -61,38 +211,64 @@ pub async fn listener() -> Result<()> {
 		.await
 		.context("failed to bind port")?;
 	info!("listening on port {}", XENON_PORT);
+	tokio::spawn(catch_context(
+		"failed to advertise over mdns",
+		crate::mdns::advertise(),
+	));
+	for address in &crate::config::CONFIG.reverse_connect {
+		tokio::spawn(reverse::dial(address.clone()));
+	}
+	serve(listener).await
+}
+
+/// Accepts tunnels on an already bound listener, without advertising it anywhere.
+/// Stops accepting once a shutdown is requested.
+pub async fn serve(listener: TcpListener) -> Result<()> {
 	loop {
-		let (socket, addr) = match listener.accept().await {
-			Ok(o) => o,
-			Err(_) => continue,
+		let (socket, addr) = tokio::select! {
+			accepted = listener.accept() => match accepted {
+				Ok(o) => o,
+				Err(_) => continue,
+			},
+			_ = shutdown::requested() => {
+				info!("no longer accepting connections");
+				return Ok(());
+			}
 		};
 		debug!("accepted connection from {}", addr);
-		let snowfall = Builder::new(NOISE_PARAMS.clone())
-			.local_private_key(&crate::keys::KEYPAIR.read().await.private)
-			.build_responder()
-			.context("failed to build encryption")?;
 		tokio::spawn(catch_context(
 			"encrypted tunnel errored",
-			connection(snowfall, socket, addr),
+			connection(socket, addr),
 		));
 	}
 }
 
-async fn connection(
-	mut snowfall: HandshakeState,
-	mut socket: TcpStream,
-	addr: SocketAddr,
-) -> Result<()> {
+// Generic over the socket, so that tunnels over something other than TCP are served the same way.
+async fn connection<T>(mut socket: T, addr: SocketAddr) -> Result<()>
+where
+	T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
 	let mut buf = Vec::<u8>::new();
 	let mut handshake_buf = vec![0u8; 65535];
-	// Before we set up Noise, we need the magic packet (wavexor'd)
-	if !handshake::read_magic(&mut socket, &mut buf)
-		.await
-		.context("failed to initialize handshake")?
-	{
-		return Ok(());
-	}
-	trace!("{} -> magic is ok", addr);
+	// Before we set up Noise, agree on a protocol version and features.
+	let negotiated = handshake::accept_hello(
+		&mut socket,
+		crate::config::CONFIG.features(),
+		crate::config::CONFIG.max_clock_skew(),
+	)
+	.await
+	.context("failed to negotiate protocol")?;
+	trace!(
+		"{} -> negotiated protocol version {}, using {} compression",
+		addr,
+		negotiated.version,
+		negotiated.compression.as_str()
+	);
+	let mut snowfall = Builder::new(NOISE_PARAMS.clone())
+		.prologue(&negotiated.prologue)
+		.local_private_key(&crate::keys::KEYPAIR.read().await.private)
+		.build_responder()
+		.context("failed to build encryption")?;
 	// Set up our Noise connection: https://noiseexplorer.com/patterns/XK
 	// 0,2
 	let msg = net::read_msg(&mut socket, &mut buf)
@@ -118,17 +294,38 @@ async fn connection(
 		.read_message(msg, &mut handshake_buf)
 		.context("failed to parse handshake message 4,5")?;
 	trace!("{} -> got 4,5", addr);
-	info!("connection established by {}", addr);
+	// XK transmits the client's static key in 4,5, make sure it's one we've paired with.
+	let client_key = snowfall
+		.get_remote_static()
+		.context("client did not send a static key")?
+		.to_vec();
+	let client_key_b64 = base64::encode_config(&client_key, base64::URL_SAFE_NO_PAD);
+	if !crate::clients::is_authorized(&client_key).await {
+		warn!(
+			"rejecting connection from {}: client {} is not authorized",
+			addr, client_key_b64
+		);
+		return handshake::send_verdict(&mut socket, Some(Rejection::UnknownClient)).await;
+	}
+	handshake::send_verdict(&mut socket, None)
+		.await
+		.context("failed to accept client")?;
+	info!("connection established by {} ({})", addr, client_key_b64);
+	let stats = stats::register(addr, client_key_b64).await;
 	// Handshake complete, start the actual connection.
-	tokio::spawn(catch_context(
-		"encrypted connection errored",
-		server(
-			snowfall
-				.into_transport_mode()
-				.context("failed to finalize encrypted connection")?,
+	let result = match snowfall.into_transport_mode() {
+		Ok(transport) => server(
+			transport,
 			socket,
 			addr,
-		),
-	));
-	Ok(())
+			client_key,
+			stats.clone(),
+			negotiated,
+		)
+		.await
+		.context("encrypted connection errored"),
+		Err(err) => Err(err).context("failed to finalize encrypted connection"),
+	};
+	stats::unregister(&stats).await;
+	result
 }
@@ -3,6 +3,7 @@
 	All rights reserved.
 */
 
+use crate::paths;
 use core::ops::Range;
 use futures::{Future, StreamExt};
 use http::StatusCode;
@@ -54,7 +55,7 @@ pub struct PhotoFs {
 impl Default for PhotoFs {
 	fn default() -> Self {
 		Self {
-			inner: *LocalFs::new("/var/mobile/Media/DCIM", true, false, true),
+			inner: *LocalFs::new(paths::mobile_dir().join("Media/DCIM"), true, false, true),
 		}
 	}
 }
@@ -99,9 +100,9 @@ impl DavFileSystem for PhotoFs {
 		meta: ReadDirMeta,
 	) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
 		Box::pin(async move {
-			let mut read_dir = tokio::fs::read_dir("/var/mobile/Media/DCIM")
+			let mut read_dir = tokio::fs::read_dir(paths::mobile_dir().join("Media/DCIM"))
 				.await
-				.expect("failed to read directory");
+				.map_err(|_| FsError::NotFound)?;
 			let mut contents = Vec::<Box<dyn DavDirEntry>>::new();
 			while let Ok(Some(dir)) = read_dir.next_entry().await {
 				let path = dir.path();
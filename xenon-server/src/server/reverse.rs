@@ -0,0 +1,52 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use tokio::{net::TcpStream, time};
+
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+const INITIAL_BACKOFF: time::Duration = time::Duration::from_secs(1);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+// Dials a client that's waiting for us, rather than the other way around.
+// Only who opens the TCP connection changes, the client still initiates the handshake and HTTP/2.
+pub async fn dial(address: String) {
+	let mut backoff = INITIAL_BACKOFF;
+	while !crate::shutdown::is_requested() {
+		let finished =
+			match time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address.as_str())).await {
+				Ok(Ok(socket)) => match socket.peer_addr() {
+					Ok(addr) => {
+						debug!("dialed client at {} ({})", address, addr);
+						match super::connection(socket, addr).await {
+							Ok(()) => true,
+							Err(err) => {
+								warn!("reverse connection to {} errored: {:?}", address, err);
+								false
+							}
+						}
+					}
+					Err(err) => {
+						warn!("failed to get address of {}: {:?}", address, err);
+						false
+					}
+				},
+				Ok(Err(err)) => {
+					debug!("failed to dial client at {}: {}", address, err);
+					false
+				}
+				Err(_) => {
+					debug!("timed out dialing client at {}", address);
+					false
+				}
+			};
+		// Sessions that ended cleanly are redialed straight away, only failures back off.
+		if finished {
+			backoff = INITIAL_BACKOFF;
+			continue;
+		}
+		time::sleep(backoff).await;
+		backoff = (backoff * 2).min(MAX_BACKOFF);
+	}
+}
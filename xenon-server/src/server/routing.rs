@@ -0,0 +1,103 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+	path.split('/').filter(|segment| !segment.is_empty())
+}
+
+// None if a segment doesn't decode to valid UTF-8, which no mount name could match anyways.
+fn decoded_segments(path: &str) -> Option<Vec<String>> {
+	segments(path)
+		.map(|segment| {
+			percent_decode_str(segment)
+				.decode_utf8()
+				.ok()
+				.map(|segment| segment.into_owned())
+		})
+		.collect()
+}
+
+/// Finds the mount a request path belongs to.
+/// Mount names have to match whole, percent-decoded path segments, and the longest match wins.
+pub fn find_mount<'a, T>(
+	mounts: &'a HashMap<String, T>,
+	path: &str,
+) -> Option<(&'a String, &'a T)> {
+	let path = decoded_segments(path)?;
+	mounts
+		.iter()
+		.filter_map(|(name, mount)| {
+			let mut len = 0;
+			for segment in segments(name) {
+				if path.get(len).map(String::as_str) != Some(segment) {
+					return None;
+				}
+				len += 1;
+			}
+			if len == 0 {
+				return None;
+			}
+			Some((len, name, mount))
+		})
+		.max_by_key(|(len, name, _)| (*len, name.len()))
+		.map(|(_, name, mount)| (name, mount))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn routes() {
+		let mounts = [
+			"Photos",
+			"PhotosBackup",
+			"Files",
+			"Files/Shared",
+			"写真",
+			"a.b-c_d",
+		]
+		.iter()
+		.map(|name| (name.to_string(), ()))
+		.collect::<HashMap<_, _>>();
+		let table: &[(&str, Option<&str>)] = &[
+			("/Photos", Some("Photos")),
+			("/Photos/", Some("Photos")),
+			("/Photos/IMG_0001.JPG", Some("Photos")),
+			("Photos/IMG_0001.JPG", Some("Photos")),
+			("//Photos//IMG_0001.JPG", Some("Photos")),
+			("/PhotosBackup", Some("PhotosBackup")),
+			("/PhotosBackup/IMG_0001.JPG", Some("PhotosBackup")),
+			("/Photosfoo", None),
+			("/Photosfoo/IMG_0001.JPG", None),
+			("/Phot", None),
+			("/photos", None),
+			("/Files/readme.txt", Some("Files")),
+			("/Files/Shared", Some("Files/Shared")),
+			("/Files/Shared/readme.txt", Some("Files/Shared")),
+			("/Files/SharedStuff", Some("Files")),
+			("/%E5%86%99%E7%9C%9F/IMG_0001.JPG", Some("写真")),
+			("/写真/IMG_0001.JPG", Some("写真")),
+			("/%50hotos/IMG_0001.JPG", Some("Photos")),
+			("/Photos%2FIMG_0001.JPG", None),
+			("/a.b-c_d/file", Some("a.b-c_d")),
+			("/%FF/file", None),
+			("/", None),
+			("", None),
+			("/nothing/here", None),
+		];
+		for (path, expected) in table {
+			assert_eq!(
+				find_mount(&mounts, path).map(|(name, _)| name.as_str()),
+				*expected,
+				"routing {:?}",
+				path
+			);
+		}
+	}
+}
@@ -0,0 +1,83 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use once_cell::sync::Lazy;
+use std::{
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Duration,
+};
+use tokio::sync::watch;
+
+static SHUTDOWN: Lazy<(watch::Sender<bool>, watch::Receiver<bool>)> =
+	Lazy::new(|| watch::channel(false));
+
+// The receiver is only woken up on changes, the count itself lives in ACTIVE.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_CHANGED: Lazy<(watch::Sender<()>, watch::Receiver<()>)> =
+	Lazy::new(|| watch::channel(()));
+
+/// Tells everything that's serving requests to wrap up.
+pub fn request() {
+	let _ = SHUTDOWN.0.send(true);
+}
+
+pub fn is_requested() -> bool {
+	*SHUTDOWN.1.borrow()
+}
+
+/// Resolves once a shutdown has been requested.
+pub async fn requested() {
+	let mut rx = SHUTDOWN.1.clone();
+	while !*rx.borrow() {
+		if rx.changed().await.is_err() {
+			return;
+		}
+	}
+}
+
+/// Resolves on SIGTERM, which is how launchd stops us, or SIGINT.
+pub async fn signalled() {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	match signal(SignalKind::terminate()) {
+		Ok(mut term) => tokio::select! {
+			_ = term.recv() => info!("got SIGTERM, shutting down"),
+			_ = tokio::signal::ctrl_c() => info!("got SIGINT, shutting down"),
+		},
+		Err(err) => {
+			error!("failed to listen for SIGTERM: {:?}", err);
+			let _ = tokio::signal::ctrl_c().await;
+			info!("got SIGINT, shutting down");
+		}
+	}
+}
+
+/// Keeps [drain] waiting for as long as it's alive.
+pub struct Active(());
+
+impl Drop for Active {
+	fn drop(&mut self) {
+		ACTIVE.fetch_sub(1, Ordering::SeqCst);
+		let _ = ACTIVE_CHANGED.0.send(());
+	}
+}
+
+pub fn track() -> Active {
+	ACTIVE.fetch_add(1, Ordering::SeqCst);
+	Active(())
+}
+
+/// Waits for everything being tracked to finish, giving up after the timeout. Returns whether it all finished.
+pub async fn drain(timeout: Duration) -> bool {
+	let mut rx = ACTIVE_CHANGED.1.clone();
+	let wait = async {
+		while ACTIVE.load(Ordering::SeqCst) > 0 {
+			if rx.changed().await.is_err() {
+				return;
+			}
+		}
+	};
+	tokio::time::timeout(timeout, wait).await.is_ok()
+}
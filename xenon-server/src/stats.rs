@@ -0,0 +1,269 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use crate::{
+	clients::AUTHORIZED_CLIENTS,
+	keys::KEYPAIR,
+	mount::{DAV_MOUNTS, MOUNT_STATUS},
+	paths,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+	collections::{BTreeMap, HashMap},
+	io::Error as IoError,
+	net::SocketAddr,
+	path::PathBuf,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	task::{Context, Poll},
+	time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	sync::RwLock,
+};
+use xenon_config::{MountAccess, MountError, MountStatus};
+use xenon_tunnel::{handshake::PROTOCOL_VERSION, XENON_PORT};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+pub static CONNECTIONS: Lazy<RwLock<HashMap<u64, Arc<ConnectionStats>>>> =
+	Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// What we know about a single established tunnel.
+pub struct ConnectionStats {
+	pub id: u64,
+	pub addr: SocketAddr,
+	pub client: String,
+	pub connected_at: SystemTime,
+	bytes_in: AtomicU64,
+	bytes_out: AtomicU64,
+	requests: Mutex<BTreeMap<String, u64>>,
+	last_error: Mutex<Option<String>>,
+}
+
+impl ConnectionStats {
+	pub fn count_request(&self, mount: &str) {
+		*self
+			.requests
+			.lock()
+			.expect("request stats were poisoned")
+			.entry(mount.to_string())
+			.or_default() += 1;
+	}
+
+	pub fn set_error(&self, err: &anyhow::Error) {
+		*self.last_error.lock().expect("error stats were poisoned") = Some(format!("{:#}", err));
+	}
+
+	fn status(&self) -> ConnectionStatus {
+		ConnectionStatus {
+			id: self.id,
+			addr: self.addr,
+			client: self.client.clone(),
+			connected_at: self
+				.connected_at
+				.duration_since(UNIX_EPOCH)
+				.map(|time| time.as_secs())
+				.unwrap_or_default(),
+			bytes_in: self.bytes_in.load(Ordering::Relaxed),
+			bytes_out: self.bytes_out.load(Ordering::Relaxed),
+			requests: self
+				.requests
+				.lock()
+				.expect("request stats were poisoned")
+				.clone(),
+			last_error: self
+				.last_error
+				.lock()
+				.expect("error stats were poisoned")
+				.clone(),
+		}
+	}
+}
+
+/// Starts tracking a connection. It has to be passed to [unregister] once it closes.
+pub async fn register(addr: SocketAddr, client: String) -> Arc<ConnectionStats> {
+	let stats = Arc::new(ConnectionStats {
+		id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+		addr,
+		client,
+		connected_at: SystemTime::now(),
+		bytes_in: AtomicU64::new(0),
+		bytes_out: AtomicU64::new(0),
+		requests: Mutex::new(BTreeMap::new()),
+		last_error: Mutex::new(None),
+	});
+	CONNECTIONS.write().await.insert(stats.id, stats.clone());
+	stats
+}
+
+pub async fn unregister(stats: &ConnectionStats) {
+	CONNECTIONS.write().await.remove(&stats.id);
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConnectionStatus {
+	id: u64,
+	addr: SocketAddr,
+	client: String,
+	// Seconds since the Unix epoch.
+	connected_at: u64,
+	bytes_in: u64,
+	bytes_out: u64,
+	requests: BTreeMap<String, u64>,
+	last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerStatus {
+	version: &'static str,
+	protocol_version: u16,
+	pubkey: String,
+	port: u16,
+	config_dir: PathBuf,
+	mounts: usize,
+	// Every mount in mounts.json, including the ones that failed to load and why.
+	mount_status: BTreeMap<String, MountStatus>,
+	authorized_clients: usize,
+	connections: Vec<ConnectionStatus>,
+}
+
+// Errors are only named, as the full ones can give away paths on the server.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientMountStatus {
+	loaded: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<&'static str>,
+}
+
+/// The server's status, as clients over the tunnel see it.
+/// They only get their own connection and the mounts they can see, the rest is for IPC.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientStatus {
+	version: &'static str,
+	protocol_version: u16,
+	pubkey: String,
+	port: u16,
+	mounts: usize,
+	mount_status: BTreeMap<String, ClientMountStatus>,
+	connection: ConnectionStatus,
+}
+
+pub async fn server_status() -> ServerStatus {
+	let mut connections = CONNECTIONS
+		.read()
+		.await
+		.values()
+		.map(|stats| stats.status())
+		.collect::<Vec<_>>();
+	connections.sort_by_key(|connection| connection.id);
+	ServerStatus {
+		version: env!("CARGO_PKG_VERSION"),
+		protocol_version: PROTOCOL_VERSION,
+		pubkey: base64::encode_config(&KEYPAIR.read().await.public, base64::URL_SAFE_NO_PAD),
+		port: XENON_PORT,
+		config_dir: paths::config_dir().to_path_buf(),
+		mounts: DAV_MOUNTS.read().await.len(),
+		mount_status: MOUNT_STATUS
+			.read()
+			.await
+			.iter()
+			.map(|(name, (_, status))| (name.clone(), status.clone()))
+			.collect(),
+		authorized_clients: AUTHORIZED_CLIENTS.read().await.len(),
+		connections,
+	}
+}
+
+pub async fn client_status(client: &[u8], connection: &ConnectionStats) -> ClientStatus {
+	ClientStatus {
+		version: env!("CARGO_PKG_VERSION"),
+		protocol_version: PROTOCOL_VERSION,
+		pubkey: base64::encode_config(&KEYPAIR.read().await.public, base64::URL_SAFE_NO_PAD),
+		port: XENON_PORT,
+		mounts: DAV_MOUNTS
+			.read()
+			.await
+			.values()
+			.filter(|mount| mount.access_for(client) != MountAccess::Hidden)
+			.count(),
+		mount_status: MOUNT_STATUS
+			.read()
+			.await
+			.iter()
+			.filter(|(_, (permissions, _))| permissions.access_for(client) != MountAccess::Hidden)
+			.map(|(name, (_, status))| {
+				(
+					name.clone(),
+					ClientMountStatus {
+						loaded: status.loaded,
+						error: status.error.as_ref().map(MountError::kind),
+					},
+				)
+			})
+			.collect(),
+		connection: connection.status(),
+	}
+}
+
+/// Counts the bytes that go over a connection's transport, before the tunnel decrypts them.
+pub struct CountedStream<T> {
+	inner: T,
+	stats: Arc<ConnectionStats>,
+}
+
+impl<T> CountedStream<T> {
+	pub fn new(inner: T, stats: Arc<ConnectionStats>) -> Self {
+		Self { inner, stats }
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountedStream<T> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<Result<(), IoError>> {
+		let before = buf.filled().len();
+		let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+		self.stats
+			.bytes_in
+			.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+		result
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountedStream<T> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, IoError>> {
+		let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+		if let Poll::Ready(Ok(written)) = result {
+			self.stats
+				.bytes_out
+				.fetch_add(written as u64, Ordering::Relaxed);
+		}
+		result
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
@@ -0,0 +1,150 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use snow::{Builder, TransportState};
+use std::io::Cursor;
+use tokio_util::codec::{Decoder, Encoder};
+use xenon_tunnel::{
+	stream::{
+		compression::{self, CompressionAlgorithm, DEFAULT_ZSTD_LEVEL},
+		snowfall::SnowfallStream,
+	},
+	NOISE_PARAMS,
+};
+
+const WRITE_SIZE: usize = 64 * 1024;
+const TOTAL_SIZE: usize = 4 * 1024 * 1024;
+
+// Runs the XK handshake in memory, as only the codec is being measured here.
+fn transports() -> (TransportState, TransportState) {
+	let server_keys = Builder::new(NOISE_PARAMS.clone())
+		.generate_keypair()
+		.unwrap();
+	let client_keys = Builder::new(NOISE_PARAMS.clone())
+		.generate_keypair()
+		.unwrap();
+	let mut initiator = Builder::new(NOISE_PARAMS.clone())
+		.local_private_key(&client_keys.private)
+		.remote_public_key(&server_keys.public)
+		.build_initiator()
+		.unwrap();
+	let mut responder = Builder::new(NOISE_PARAMS.clone())
+		.local_private_key(&server_keys.private)
+		.build_responder()
+		.unwrap();
+	let mut msg = vec![0u8; 65535];
+	let mut payload = vec![0u8; 65535];
+	let len = initiator.write_message(&[], &mut msg).unwrap();
+	responder.read_message(&msg[..len], &mut payload).unwrap();
+	let len = responder.write_message(&[], &mut msg).unwrap();
+	initiator.read_message(&msg[..len], &mut payload).unwrap();
+	let len = initiator.write_message(&[], &mut msg).unwrap();
+	responder.read_message(&msg[..len], &mut payload).unwrap();
+	(
+		initiator.into_transport_mode().unwrap(),
+		responder.into_transport_mode().unwrap(),
+	)
+}
+
+// Photos and videos are already compressed, so most real traffic looks like noise.
+fn incompressible_data(len: usize) -> Vec<u8> {
+	let mut state = 0x2545_f491_4f6c_dd1d_u64;
+	(0..len)
+		.map(|_| {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state as u8
+		})
+		.collect()
+}
+
+fn compressible_data(len: usize) -> Vec<u8> {
+	b"<D:propstat><D:prop><D:getcontentlength>4096</D:getcontentlength></D:prop></D:propstat>"
+		.iter()
+		.copied()
+		.cycle()
+		.take(len)
+		.collect()
+}
+
+// Costs what decoding used to: the ciphertext, the plaintext and the returned frame were each copied.
+fn copy_like_before(frame: &[u8], compression_buf: &mut Vec<u8>) -> Cursor<Vec<u8>> {
+	let data = frame.to_vec();
+	compression_buf.clear();
+	compression_buf.extend_from_slice(&data);
+	Cursor::new(compression_buf.clone())
+}
+
+fn round_trip(c: &mut Criterion) {
+	let mut group = c.benchmark_group("round_trip");
+	group.throughput(Throughput::Bytes(TOTAL_SIZE as u64));
+	for (kind, data) in &[
+		("incompressible", incompressible_data(WRITE_SIZE)),
+		("compressible", compressible_data(WRITE_SIZE)),
+	] {
+		for algorithm in CompressionAlgorithm::SUPPORTED {
+			let (client, server) = transports();
+			let mut encoder = SnowfallStream::new(
+				client,
+				compression::from_algorithm(*algorithm, DEFAULT_ZSTD_LEVEL),
+			);
+			let mut decoder = SnowfallStream::new(
+				server,
+				compression::from_algorithm(*algorithm, DEFAULT_ZSTD_LEVEL),
+			);
+			let mut wire = BytesMut::with_capacity(WRITE_SIZE * 2);
+			group.bench_with_input(
+				BenchmarkId::new(*kind, algorithm.as_str()),
+				data,
+				|b, data| {
+					b.iter(|| {
+						let mut received = 0;
+						while received < TOTAL_SIZE {
+							encoder.encode(data.as_slice(), &mut wire).unwrap();
+							while let Some(frame) = decoder.decode(&mut wire).unwrap() {
+								received += frame.len();
+							}
+						}
+						received
+					})
+				},
+			);
+		}
+
+		// The baseline the others are compared against: no compression, copying every frame.
+		let (client, server) = transports();
+		let mut encoder = SnowfallStream::new(
+			client,
+			compression::from_algorithm(CompressionAlgorithm::None, DEFAULT_ZSTD_LEVEL),
+		);
+		let mut decoder = SnowfallStream::new(
+			server,
+			compression::from_algorithm(CompressionAlgorithm::None, DEFAULT_ZSTD_LEVEL),
+		);
+		let mut wire = BytesMut::with_capacity(WRITE_SIZE * 2);
+		let mut compression_buf = Vec::with_capacity(WRITE_SIZE);
+		group.bench_with_input(BenchmarkId::new(*kind, "baseline"), data, |b, data| {
+			b.iter(|| {
+				let mut received = 0;
+				while received < TOTAL_SIZE {
+					encoder.encode(data.as_slice(), &mut wire).unwrap();
+					while let Some(frame) = decoder.decode(&mut wire).unwrap() {
+						received += copy_like_before(&frame, &mut compression_buf)
+							.get_ref()
+							.len();
+					}
+				}
+				received
+			})
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, round_trip);
+criterion_main!(benches);
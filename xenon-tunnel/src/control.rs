@@ -0,0 +1,85 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	time,
+};
+
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+// Control messages are tiny, anything bigger than this is garbage.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Messages sent over the control stream of a multiplexed tunnel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+	Ping(u64),
+	Pong(u64),
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(io: &mut W, msg: &ControlMessage) -> Result<()> {
+	let msg = rmp_serde::to_vec_named(msg).context("failed to encode control message")?;
+	io.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+	io.write_all(&msg).await?;
+	io.flush().await?;
+	Ok(())
+}
+
+/// Reads the next control message, or None if the stream was closed.
+pub async fn read_message<R: AsyncRead + Unpin>(io: &mut R) -> Result<Option<ControlMessage>> {
+	let mut len = [0u8; std::mem::size_of::<u32>()];
+	match io.read_exact(&mut len).await {
+		Ok(_) => {}
+		Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(err) => return Err(err).context("failed to read control message"),
+	}
+	let len = u32::from_be_bytes(len) as usize;
+	if len > MAX_MESSAGE_SIZE {
+		anyhow::bail!("control message is too large ({} bytes)", len);
+	}
+	let mut msg = vec![0u8; len];
+	io.read_exact(&mut msg)
+		.await
+		.context("failed to read control message")?;
+	rmp_serde::from_slice(&msg)
+		.map(Some)
+		.context("failed to decode control message")
+}
+
+/// Pings the other side every so often, erroring if it stops answering.
+pub async fn keepalive<T: AsyncRead + AsyncWrite + Unpin>(mut io: T) -> Result<()> {
+	let mut interval = time::interval(KEEPALIVE_INTERVAL);
+	let mut sequence = 0u64;
+	loop {
+		interval.tick().await;
+		sequence += 1;
+		write_message(&mut io, &ControlMessage::Ping(sequence)).await?;
+		match time::timeout(KEEPALIVE_TIMEOUT, read_message(&mut io))
+			.await
+			.context("peer did not answer keepalive in time")??
+		{
+			Some(ControlMessage::Pong(pong)) if pong == sequence => {}
+			Some(other) => anyhow::bail!("unexpected keepalive reply: {:?}", other),
+			None => anyhow::bail!("peer closed the control stream"),
+		}
+	}
+}
+
+/// Answers the other side's control messages until it closes the stream.
+pub async fn respond<T: AsyncRead + AsyncWrite + Unpin>(mut io: T) -> Result<()> {
+	while let Some(msg) = read_message(&mut io).await? {
+		match msg {
+			ControlMessage::Ping(sequence) => {
+				write_message(&mut io, &ControlMessage::Pong(sequence)).await?
+			}
+			ControlMessage::Pong(_) => {}
+		}
+	}
+	Ok(())
+}
@@ -3,100 +3,383 @@
 	All rights reserved.
 */
 
-use crate::MAGIC_INITIALIZER;
-use anyhow::Result;
-use rand::RngCore;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	net::TcpStream,
+use crate::{net, stream::snowfall::RekeyPolicy, MAX_FRAME_SIZE};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+	convert::TryFrom,
+	fmt,
+	str::FromStr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub async fn read_magic(socket: &mut TcpStream, buf: &mut Vec<u8>) -> Result<bool> {
-	let magic = MAGIC_INITIALIZER;
-	let magic_len = magic.len();
-	let mut magic_key = vec![0u8; magic_len];
-	let mut magicked_epoch = [0u8; std::mem::size_of::<u128>()];
-	let mut epoch_key = [0u8; std::mem::size_of::<u128>()];
-	socket.read_exact(&mut epoch_key).await?;
-	socket.read_exact(&mut magicked_epoch).await?;
-	socket.read_exact(&mut magic_key).await?;
-
-	magic_key.reverse();
-	epoch_key.reverse();
-
-	epoch_key
-		.iter_mut()
-		.for_each(|byte| *byte = byte.reverse_bits() ^ 42);
-
-	magicked_epoch
-		.iter_mut()
-		.enumerate()
-		.for_each(|(idx, byte)| *byte = byte.reverse_bits() ^ epoch_key[idx]);
-
-	magic_key.iter_mut().enumerate().for_each(|(idx, byte)| {
-		*byte = byte.reverse_bits() ^ magicked_epoch[idx % std::mem::size_of::<u128>()];
-	});
-
-	let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
-	let epoch = u128::from_le_bytes(magicked_epoch);
-
-	if now > epoch + 5000000000 {
-		return Ok(false);
+// Sent raw before anything else, so either side can tell a Xenon peer (and its version) apart from garbage.
+pub const PROTOCOL_MAGIC: &[u8; 5] = b"XENON";
+// Version 1 was the obfuscated magic preamble, which can't be negotiated with.
+// Version 2 flags whether each frame is compressed, version 3 adds rekey frames.
+pub const PROTOCOL_VERSION: u16 = 3;
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+// Older peers would treat rekey frames as garbage, so we never switch keys with them.
+const REKEY_VERSION: u16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+	Lz4,
+	Zstd,
+	None,
+}
+
+impl CompressionAlgorithm {
+	/// All algorithms we support, in order of preference.
+	pub const SUPPORTED: &'static [CompressionAlgorithm] = &[
+		CompressionAlgorithm::Lz4,
+		CompressionAlgorithm::Zstd,
+		CompressionAlgorithm::None,
+	];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			CompressionAlgorithm::Lz4 => "lz4",
+			CompressionAlgorithm::Zstd => "zstd",
+			CompressionAlgorithm::None => "none",
+		}
 	}
+}
+
+impl FromStr for CompressionAlgorithm {
+	type Err = anyhow::Error;
 
-	if magic_len > buf.len() {
-		buf.resize(magic_len, 0);
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"lz4" => Ok(CompressionAlgorithm::Lz4),
+			"zstd" => Ok(CompressionAlgorithm::Zstd),
+			"none" => Ok(CompressionAlgorithm::None),
+			_ => anyhow::bail!("unknown compression algorithm '{}'", s),
+		}
 	}
+}
 
-	let buf = &mut buf[..magic_len];
-	socket.read_exact(buf).await?;
+// Algorithms are sent as strings, so that peers can skip over ones they don't know.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Features {
+	pub compression: Vec<String>,
+	pub max_frame_size: u32,
+	// Whether the tunnel is split into streams by the multiplexer, rather than carrying HTTP/2 directly.
+	#[serde(default)]
+	pub multiplex: bool,
+}
 
-	buf.iter_mut()
-		.enumerate()
-		.for_each(|(idx, byte)| *byte = byte.reverse_bits() ^ magic_key[idx]);
+impl Features {
+	/// Our features, preferring the given compression algorithms in that order.
+	pub fn with_compression(compression: &[CompressionAlgorithm]) -> Self {
+		Self {
+			compression: compression
+				.iter()
+				.map(|algorithm| algorithm.as_str().to_string())
+				.collect(),
+			max_frame_size: MAX_FRAME_SIZE,
+			multiplex: true,
+		}
+	}
+}
 
-	Ok(magic.as_bytes() == buf)
+impl Default for Features {
+	fn default() -> Self {
+		Self::with_compression(CompressionAlgorithm::SUPPORTED)
+	}
 }
 
-pub async fn write_magic(socket: &mut TcpStream) -> Result<()> {
-	let magic = MAGIC_INITIALIZER;
-	let magic_len = magic.len();
-	let mut magic_key = vec![0u8; magic_len];
-	let mut epoch_key = [0u8; std::mem::size_of::<u128>()];
-	rand::thread_rng().fill_bytes(&mut magic_key);
-	rand::thread_rng().fill_bytes(&mut epoch_key);
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+	pub version: u16,
+	pub features: Features,
+	// Milliseconds since the UNIX epoch, checked against the server's clock.
+	pub timestamp: u64,
+}
 
-	let epoch_val = SystemTime::now()
-		.duration_since(UNIX_EPOCH)?
-		.as_nanos()
-		.to_le_bytes();
-	let epoch = epoch_val
-		.iter()
-		.enumerate()
-		.map(|(idx, byte)| (byte ^ epoch_key[idx]).reverse_bits())
-		.collect::<Vec<u8>>();
-	let magic = magic
-		.as_bytes()
+/// Why a server refused a client, sent to the client so it can tell the user.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+	BadMagic,
+	VersionMismatch { version: u16, min: u16, max: u16 },
+	ClockSkew { skew_ms: i64, tolerance_ms: u64 },
+	UnknownClient,
+	Unsupported(String),
+}
+
+impl fmt::Display for Rejection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Rejection::BadMagic => write!(
+				f,
+				"bad magic, peer is either not Xenon or running an older version of it"
+			),
+			Rejection::VersionMismatch { version, min, max } => write!(
+				f,
+				"protocol version {} is not supported, the server supports versions {} through {}",
+				version, min, max
+			),
+			Rejection::ClockSkew {
+				skew_ms,
+				tolerance_ms,
+			} => write!(
+				f,
+				"clocks differ by {:.1} seconds, which is more than the allowed {:.1} seconds",
+				*skew_ms as f64 / 1000.0,
+				*tolerance_ms as f64 / 1000.0
+			),
+			Rejection::UnknownClient => {
+				write!(f, "this client has not been paired with the server")
+			}
+			Rejection::Unsupported(reason) => write!(f, "{}", reason),
+		}
+	}
+}
+
+impl std::error::Error for Rejection {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Accepted {
+	pub version: u16,
+	pub compression: String,
+	pub max_frame_size: u32,
+	#[serde(default)]
+	pub multiplex: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum HelloReply {
+	Accepted(Accepted),
+	Rejected(Rejection),
+}
+
+// Sent by the server after the Noise handshake, once it knows who the client is.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Verdict {
+	Accepted,
+	Rejected(Rejection),
+}
+
+/// The outcome of protocol negotiation, shared by both sides.
+#[derive(Clone, Debug)]
+pub struct Negotiated {
+	pub version: u16,
+	pub compression: CompressionAlgorithm,
+	pub max_frame_size: u32,
+	pub multiplex: bool,
+	/// Both hello messages, which must be passed as the Noise prologue so that tampering with them breaks the handshake.
+	pub prologue: Vec<u8>,
+}
+
+async fn write_magic<S: AsyncWrite + Unpin>(socket: &mut S) -> Result<()> {
+	socket.write_all(PROTOCOL_MAGIC).await?;
+	socket.write_all(&PROTOCOL_VERSION.to_be_bytes()).await?;
+	Ok(())
+}
+
+fn now_millis() -> Result<u64> {
+	Ok(SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.context("system clock is before the UNIX epoch")?
+		.as_millis() as u64)
+}
+
+async fn read_magic<S: AsyncRead + Unpin>(socket: &mut S) -> Result<u16> {
+	let mut magic = [0u8; PROTOCOL_MAGIC.len()];
+	socket.read_exact(&mut magic).await?;
+	if &magic != PROTOCOL_MAGIC {
+		return Err(Rejection::BadMagic.into());
+	}
+	let mut version = [0u8; std::mem::size_of::<u16>()];
+	socket.read_exact(&mut version).await?;
+	Ok(u16::from_be_bytes(version))
+}
+
+fn negotiate(
+	hello: &Hello,
+	ours: &Features,
+	max_clock_skew: Duration,
+) -> std::result::Result<Accepted, Rejection> {
+	if hello.version < MIN_PROTOCOL_VERSION {
+		return Err(Rejection::VersionMismatch {
+			version: hello.version,
+			min: MIN_PROTOCOL_VERSION,
+			max: PROTOCOL_VERSION,
+		});
+	}
+	// A zero tolerance disables the check entirely. Clocks may be off in either direction.
+	if max_clock_skew != Duration::from_secs(0) {
+		let now = now_millis().map_err(|err| Rejection::Unsupported(err.to_string()))?;
+		// Timestamps come from the peer, so they can be anything up to u64::MAX.
+		let skew_ms = i128::from(now) - i128::from(hello.timestamp);
+		let tolerance_ms = max_clock_skew.as_millis() as u64;
+		if skew_ms.unsigned_abs() > u128::from(tolerance_ms) {
+			return Err(Rejection::ClockSkew {
+				skew_ms: skew_ms.clamp(i64::MIN.into(), i64::MAX.into()) as i64,
+				tolerance_ms,
+			});
+		}
+	}
+	let compression = ours
+		.compression
 		.iter()
-		.enumerate()
-		.map(|(idx, byte)| (byte ^ magic_key[idx]).reverse_bits())
-		.collect::<Vec<u8>>();
-
-	magic_key.iter_mut().enumerate().for_each(|(idx, byte)| {
-		*byte = (*byte ^ epoch_val[idx % std::mem::size_of::<u128>()]).reverse_bits()
-	});
-	epoch_key
-		.iter_mut()
-		.for_each(|byte| *byte = (*byte ^ 42).reverse_bits());
-	magic_key.reverse();
-	epoch_key.reverse();
-
-	socket.write_all(&epoch_key).await?;
-	socket.write_all(&epoch).await?;
-	socket.write_all(&magic_key).await?;
-	socket.write_all(&magic).await?;
+		.find(|ours| hello.features.compression.contains(ours))
+		.ok_or_else(|| {
+			Rejection::Unsupported(format!(
+				"no common compression algorithm (client supports {:?}, server supports {:?})",
+				hello.features.compression, ours.compression
+			))
+		})?;
+	Ok(Accepted {
+		version: hello.version.min(PROTOCOL_VERSION),
+		compression: compression.clone(),
+		max_frame_size: hello.features.max_frame_size.min(ours.max_frame_size),
+		multiplex: hello.features.multiplex && ours.multiplex,
+	})
+}
+
+impl Negotiated {
+	/// How often to switch keys, which is never if the peer doesn't know how to.
+	pub fn rekey_policy(&self) -> RekeyPolicy {
+		if self.version >= REKEY_VERSION {
+			RekeyPolicy::default()
+		} else {
+			RekeyPolicy::NEVER
+		}
+	}
+}
+
+impl TryFrom<(Accepted, Vec<u8>)> for Negotiated {
+	type Error = anyhow::Error;
+
+	fn try_from((accepted, prologue): (Accepted, Vec<u8>)) -> Result<Self> {
+		if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&accepted.version) {
+			anyhow::bail!(Rejection::VersionMismatch {
+				version: accepted.version,
+				min: MIN_PROTOCOL_VERSION,
+				max: PROTOCOL_VERSION,
+			});
+		}
+		Ok(Self {
+			version: accepted.version,
+			compression: accepted.compression.parse()?,
+			max_frame_size: accepted.max_frame_size,
+			multiplex: accepted.multiplex,
+			prologue,
+		})
+	}
+}
+
+/// Client side of protocol negotiation.
+pub async fn send_hello<S: AsyncRead + AsyncWrite + Unpin>(
+	socket: &mut S,
+	features: Features,
+) -> Result<Negotiated> {
+	let mut buf = Vec::<u8>::new();
+	let hello = rmp_serde::to_vec_named(&Hello {
+		version: PROTOCOL_VERSION,
+		features,
+		timestamp: now_millis()?,
+	})
+	.context("failed to encode hello")?;
+	write_magic(socket)
+		.await
+		.context("failed to send protocol magic")?;
+	net::write_msg(socket, &hello)
+		.await
+		.context("failed to send hello")?;
+	socket.flush().await?;
+
+	let version = read_magic(socket)
+		.await
+		.context("failed to read reply, the server may be running an older version of Xenon")?;
+	let reply = net::read_msg(socket, &mut buf)
+		.await
+		.context("failed to read hello reply")?;
+	let mut prologue = hello;
+	prologue.extend_from_slice(reply);
+	match rmp_serde::from_slice::<HelloReply>(reply).context("failed to decode hello reply")? {
+		HelloReply::Accepted(accepted) => Negotiated::try_from((accepted, prologue)),
+		HelloReply::Rejected(rejection) => Err(anyhow::Error::new(rejection).context(format!(
+			"server (protocol version {}) rejected connection",
+			version
+		))),
+	}
+}
+
+async fn send_reply<S: AsyncWrite + Unpin>(socket: &mut S, reply: &HelloReply) -> Result<Vec<u8>> {
+	let reply = rmp_serde::to_vec_named(reply).context("failed to encode hello reply")?;
+	write_magic(socket)
+		.await
+		.context("failed to send protocol magic")?;
+	net::write_msg(socket, &reply)
+		.await
+		.context("failed to send hello reply")?;
 	socket.flush().await?;
+	Ok(reply)
+}
 
+/// Server side of protocol negotiation. Peers we can't talk to are sent the reason before erroring.
+pub async fn accept_hello<S: AsyncRead + AsyncWrite + Unpin>(
+	socket: &mut S,
+	features: Features,
+	max_clock_skew: Duration,
+) -> Result<Negotiated> {
+	let mut buf = Vec::<u8>::new();
+	if let Err(err) = read_magic(socket).await {
+		if let Some(rejection) = err.downcast_ref::<Rejection>() {
+			send_reply(socket, &HelloReply::Rejected(rejection.clone())).await?;
+		}
+		return Err(err.context("failed to read protocol magic"));
+	}
+	let hello = net::read_msg(socket, &mut buf)
+		.await
+		.context("failed to read hello")?
+		.to_vec();
+	let result = rmp_serde::from_slice::<Hello>(&hello)
+		.map_err(|err| Rejection::Unsupported(format!("failed to decode hello: {}", err)))
+		.and_then(|client_hello| negotiate(&client_hello, &features, max_clock_skew));
+	let reply = match &result {
+		Ok(accepted) => HelloReply::Accepted(accepted.clone()),
+		Err(rejection) => HelloReply::Rejected(rejection.clone()),
+	};
+	let reply = send_reply(socket, &reply).await?;
+
+	let accepted = result.context("rejected client")?;
+	let mut prologue = hello;
+	prologue.extend_from_slice(&reply);
+	Negotiated::try_from((accepted, prologue))
+}
+
+/// Tells the client whether it's allowed in, after the Noise handshake has revealed who it is.
+pub async fn send_verdict<S: AsyncWrite + Unpin>(
+	socket: &mut S,
+	rejection: Option<Rejection>,
+) -> Result<()> {
+	let verdict = match rejection {
+		Some(rejection) => Verdict::Rejected(rejection),
+		None => Verdict::Accepted,
+	};
+	net::write_msg(
+		socket,
+		&rmp_serde::to_vec_named(&verdict).context("failed to encode verdict")?,
+	)
+	.await
+	.context("failed to send verdict")?;
+	socket.flush().await?;
 	Ok(())
 }
+
+pub async fn read_verdict<S: AsyncRead + Unpin>(socket: &mut S) -> Result<()> {
+	let mut buf = Vec::<u8>::new();
+	let verdict = net::read_msg(socket, &mut buf)
+		.await
+		.context("failed to read verdict")?;
+	match rmp_serde::from_slice::<Verdict>(verdict).context("failed to decode verdict")? {
+		Verdict::Accepted => Ok(()),
+		Verdict::Rejected(rejection) => {
+			Err(anyhow::Error::new(rejection).context("server rejected connection"))
+		}
+	}
+}
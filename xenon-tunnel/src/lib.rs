@@ -14,18 +14,21 @@
 #[macro_use]
 extern crate obfstr;
 
+pub mod control;
 pub mod handshake;
+pub mod mux;
 pub mod net;
 pub mod stream;
 
-pub use stream::EncryptedTcpStream;
+pub use stream::{EncryptedStream, EncryptedTcpStream};
 
 use once_cell::sync::Lazy;
 use snow::params::NoiseParams;
 
-pub const MAGIC_INITIALIZER: &str = include_str!("magic.key");
 // (42 xor 7,500,000) modulo 65535
 pub const XENON_PORT: u16 = 28988;
+// Servers advertise themselves under this mDNS service type, with their base64 public key in the "pubkey" TXT record.
+pub const MDNS_SERVICE_TYPE: &str = "_xenon._tcp.local.";
 pub const SIZE_LIMIT: usize = u16::MAX as usize;
 // NPF maximum size is 65535. So this should be enough to cover compression + headers.
 pub const MAX_FRAME_SIZE: u32 = 61440;
@@ -0,0 +1,544 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::FutureExt;
+use std::{
+	collections::{HashMap, VecDeque},
+	future::Future,
+	io::{Error as IoError, ErrorKind as IoErrorKind},
+	pin::Pin,
+	sync::{Arc, Mutex, MutexGuard},
+	task::{Context, Poll, Waker},
+};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+	sync::mpsc,
+};
+
+// Every frame starts with [type u8][stream id u32][length u32], all big-endian.
+// Window updates put the increment in the length field, and carry no payload.
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_WINDOW_UPDATE: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+const HEADER_LEN: usize = 9;
+/// The most data sent in a single frame.
+pub const MAX_PAYLOAD: usize = 16 * 1024;
+/// How much data either side of a stream may have in flight before the other side reads it.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// The most streams either side may have open at once. Peers opening more get them reset.
+pub const MAX_STREAMS: usize = 256;
+// Queued frames are written together up to roughly this size, so that they share Noise messages.
+const WRITE_BATCH: usize = 60 * 1024;
+
+/// What a stream is for, sent by whoever opens it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Service {
+	Http,
+	Control,
+	Other(u16),
+}
+
+impl From<u16> for Service {
+	fn from(service: u16) -> Self {
+		match service {
+			0 => Service::Http,
+			1 => Service::Control,
+			other => Service::Other(other),
+		}
+	}
+}
+
+impl From<Service> for u16 {
+	fn from(service: Service) -> Self {
+		match service {
+			Service::Http => 0,
+			Service::Control => 1,
+			Service::Other(other) => other,
+		}
+	}
+}
+
+/// Which end of the tunnel we are. Initiators use odd stream IDs, responders use even ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+	Initiator,
+	Responder,
+}
+
+enum Frame {
+	Open { id: u32, service: Service },
+	Data { id: u32, data: Bytes },
+	WindowUpdate { id: u32, increment: u32 },
+	Close { id: u32 },
+}
+
+impl Frame {
+	fn encode(self, dst: &mut BytesMut) {
+		match self {
+			Frame::Open { id, service } => {
+				dst.put_u8(FRAME_OPEN);
+				dst.put_u32(id);
+				dst.put_u32(2);
+				dst.put_u16(service.into());
+			}
+			Frame::Data { id, data } => {
+				dst.put_u8(FRAME_DATA);
+				dst.put_u32(id);
+				dst.put_u32(data.len() as u32);
+				dst.extend_from_slice(&data);
+			}
+			Frame::WindowUpdate { id, increment } => {
+				dst.put_u8(FRAME_WINDOW_UPDATE);
+				dst.put_u32(id);
+				dst.put_u32(increment);
+			}
+			Frame::Close { id } => {
+				dst.put_u8(FRAME_CLOSE);
+				dst.put_u32(id);
+				dst.put_u32(0);
+			}
+		}
+	}
+
+	// Returns None once the other side has closed the tunnel.
+	async fn read<R: AsyncRead + Unpin>(io: &mut R) -> Result<Option<Self>, IoError> {
+		let mut header = [0u8; HEADER_LEN];
+		match io.read_exact(&mut header).await {
+			Ok(_) => {}
+			Err(err) if err.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+			Err(err) => return Err(err),
+		}
+		let mut header = &header[..];
+		let kind = header.get_u8();
+		let id = header.get_u32();
+		let len = header.get_u32();
+		let frame = match kind {
+			FRAME_OPEN => {
+				let mut service = read_payload(io, len).await?;
+				if service.len() != 2 {
+					return Err(IoError::new(
+						IoErrorKind::InvalidData,
+						"invalid mux open frame",
+					));
+				}
+				Frame::Open {
+					id,
+					service: service.get_u16().into(),
+				}
+			}
+			FRAME_DATA => Frame::Data {
+				id,
+				data: read_payload(io, len).await?,
+			},
+			FRAME_WINDOW_UPDATE => Frame::WindowUpdate { id, increment: len },
+			FRAME_CLOSE => Frame::Close { id },
+			_ => {
+				return Err(IoError::new(
+					IoErrorKind::InvalidData,
+					"unknown mux frame type",
+				))
+			}
+		};
+		Ok(Some(frame))
+	}
+}
+
+async fn read_payload<R: AsyncRead + Unpin>(io: &mut R, len: u32) -> Result<Bytes, IoError> {
+	if len as usize > MAX_PAYLOAD {
+		return Err(IoError::new(
+			IoErrorKind::InvalidData,
+			"mux frame is too large",
+		));
+	}
+	let mut payload = vec![0u8; len as usize];
+	io.read_exact(&mut payload).await?;
+	Ok(Bytes::from(payload))
+}
+
+struct StreamState {
+	recv_buf: VecDeque<Bytes>,
+	// How much more the peer may send us, and how much we've read but not given back yet.
+	recv_window: u32,
+	unacked: u32,
+	send_window: u32,
+	remote_closed: bool,
+	local_closed: bool,
+	read_waker: Option<Waker>,
+	write_waker: Option<Waker>,
+}
+
+impl StreamState {
+	fn new() -> Self {
+		Self {
+			recv_buf: VecDeque::new(),
+			recv_window: INITIAL_WINDOW,
+			unacked: 0,
+			send_window: INITIAL_WINDOW,
+			remote_closed: false,
+			local_closed: false,
+			read_waker: None,
+			write_waker: None,
+		}
+	}
+
+	fn wake(&mut self) {
+		if let Some(waker) = self.read_waker.take() {
+			waker.wake();
+		}
+		if let Some(waker) = self.write_waker.take() {
+			waker.wake();
+		}
+	}
+}
+
+struct Shared {
+	streams: HashMap<u32, StreamState>,
+	next_id: u32,
+	role: Role,
+	closed: bool,
+}
+
+impl Shared {
+	// Initiators use odd stream IDs, responders use even ones.
+	fn is_ours(&self, id: u32) -> bool {
+		(id % 2 == 1) == (self.role == Role::Initiator)
+	}
+
+	fn open_streams(&self, ours: bool) -> usize {
+		self.streams
+			.keys()
+			.filter(|id| self.is_ours(**id) == ours)
+			.count()
+	}
+}
+
+type SharedState = Arc<Mutex<Shared>>;
+
+fn lock(shared: &SharedState) -> MutexGuard<'_, Shared> {
+	shared.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Opens streams over a tunnel. Cloning it is cheap.
+#[derive(Clone)]
+pub struct Multiplexer {
+	shared: SharedState,
+	tx: mpsc::UnboundedSender<Frame>,
+}
+
+/// Streams the other side of the tunnel opened.
+pub struct Incoming {
+	rx: mpsc::UnboundedReceiver<(Service, MuxStream)>,
+}
+
+impl Incoming {
+	/// Waits for the peer to open a stream. Returns None once the tunnel is closed.
+	pub async fn accept(&mut self) -> Option<(Service, MuxStream)> {
+		self.rx.recv().await
+	}
+}
+
+impl Multiplexer {
+	/// Splits `io` into streams. Nothing happens until the returned future is polled,
+	/// which runs until the tunnel closes.
+	pub fn new<T>(
+		io: T,
+		role: Role,
+	) -> (
+		Self,
+		Incoming,
+		impl Future<Output = Result<(), IoError>> + Send + 'static,
+	)
+	where
+		T: AsyncRead + AsyncWrite + Send + 'static,
+	{
+		let shared = Arc::new(Mutex::new(Shared {
+			streams: HashMap::new(),
+			next_id: match role {
+				Role::Initiator => 1,
+				Role::Responder => 2,
+			},
+			role,
+			closed: false,
+		}));
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+		let driver = drive(io, shared.clone(), tx.clone(), rx, incoming_tx);
+		(Self { shared, tx }, Incoming { rx: incoming_rx }, driver)
+	}
+
+	pub fn open(&self, service: Service) -> Result<MuxStream, IoError> {
+		let mut shared = lock(&self.shared);
+		if shared.closed {
+			return Err(IoError::new(IoErrorKind::NotConnected, "tunnel is closed"));
+		}
+		if shared.open_streams(true) >= MAX_STREAMS {
+			return Err(IoError::new(
+				IoErrorKind::Other,
+				"too many streams are open",
+			));
+		}
+		let id = shared.next_id;
+		shared.next_id = id
+			.checked_add(2)
+			.ok_or_else(|| IoError::new(IoErrorKind::Other, "ran out of stream ids"))?;
+		shared.streams.insert(id, StreamState::new());
+		self.tx
+			.send(Frame::Open { id, service })
+			.map_err(|_| IoError::new(IoErrorKind::NotConnected, "tunnel is closed"))?;
+		Ok(MuxStream {
+			id,
+			shared: self.shared.clone(),
+			tx: self.tx.clone(),
+		})
+	}
+}
+
+// Wakes every stream once the tunnel is gone, even if the driver was cancelled.
+struct CloseOnDrop(SharedState);
+
+impl Drop for CloseOnDrop {
+	fn drop(&mut self) {
+		let mut shared = lock(&self.0);
+		shared.closed = true;
+		shared.streams.values_mut().for_each(StreamState::wake);
+	}
+}
+
+async fn drive<T>(
+	io: T,
+	shared: SharedState,
+	tx: mpsc::UnboundedSender<Frame>,
+	rx: mpsc::UnboundedReceiver<Frame>,
+	incoming: mpsc::UnboundedSender<(Service, MuxStream)>,
+) -> Result<(), IoError>
+where
+	T: AsyncRead + AsyncWrite + Send + 'static,
+{
+	let _guard = CloseOnDrop(shared.clone());
+	let (reader, writer) = tokio::io::split(io);
+	tokio::select! {
+		result = read_loop(reader, shared, tx, incoming) => result,
+		result = write_loop(writer, rx) => result,
+	}
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+	mut reader: R,
+	shared: SharedState,
+	tx: mpsc::UnboundedSender<Frame>,
+	incoming: mpsc::UnboundedSender<(Service, MuxStream)>,
+) -> Result<(), IoError> {
+	while let Some(frame) = Frame::read(&mut reader).await? {
+		let mut state = lock(&shared);
+		match frame {
+			Frame::Open { id, service } => {
+				// Peers must stick to their half of the stream IDs, so they can't clash with ours.
+				if state.is_ours(id) || state.streams.contains_key(&id) {
+					return Err(IoError::new(
+						IoErrorKind::InvalidData,
+						"peer opened a stream with an invalid id",
+					));
+				}
+				// Closing it straight away resets it, without keeping any state around for it.
+				if state.open_streams(false) >= MAX_STREAMS {
+					let _ = tx.send(Frame::Close { id });
+					continue;
+				}
+				state.streams.insert(id, StreamState::new());
+				drop(state);
+				// If nobody's accepting, the stream is dropped here, which closes it.
+				let _ = incoming.send((
+					service,
+					MuxStream {
+						id,
+						shared: shared.clone(),
+						tx: tx.clone(),
+					},
+				));
+			}
+			Frame::Data { id, data } => {
+				// Streams we've already dropped just have their data discarded.
+				if let Some(stream) = state.streams.get_mut(&id) {
+					if data.len() as u32 > stream.recv_window {
+						return Err(IoError::new(
+							IoErrorKind::InvalidData,
+							"peer sent more than the stream window allows",
+						));
+					}
+					stream.recv_window -= data.len() as u32;
+					stream.recv_buf.push_back(data);
+					stream.wake();
+				}
+			}
+			Frame::WindowUpdate { id, increment } => {
+				if let Some(stream) = state.streams.get_mut(&id) {
+					stream.send_window = stream.send_window.saturating_add(increment);
+					stream.wake();
+				}
+			}
+			Frame::Close { id } => {
+				if let Some(stream) = state.streams.get_mut(&id) {
+					stream.remote_closed = true;
+					stream.wake();
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+async fn write_loop<W: AsyncWrite + Unpin>(
+	mut writer: W,
+	mut rx: mpsc::UnboundedReceiver<Frame>,
+) -> Result<(), IoError> {
+	let mut buf = BytesMut::with_capacity(WRITE_BATCH + HEADER_LEN + MAX_PAYLOAD);
+	while let Some(frame) = rx.recv().await {
+		buf.clear();
+		frame.encode(&mut buf);
+		while buf.len() < WRITE_BATCH {
+			match rx.recv().now_or_never() {
+				Some(Some(frame)) => frame.encode(&mut buf),
+				_ => break,
+			}
+		}
+		writer.write_all(&buf).await?;
+		writer.flush().await?;
+	}
+	Ok(())
+}
+
+/// One logical stream inside a tunnel.
+pub struct MuxStream {
+	id: u32,
+	shared: SharedState,
+	tx: mpsc::UnboundedSender<Frame>,
+}
+
+impl MuxStream {
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+}
+
+fn closed_error() -> IoError {
+	IoError::new(IoErrorKind::ConnectionReset, "tunnel is closed")
+}
+
+impl AsyncRead for MuxStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<Result<(), IoError>> {
+		let mut shared = lock(&self.shared);
+		let closed = shared.closed;
+		let stream = match shared.streams.get_mut(&self.id) {
+			Some(stream) => stream,
+			None => return Poll::Ready(Err(closed_error())),
+		};
+		if stream.recv_buf.is_empty() {
+			return if stream.remote_closed {
+				Poll::Ready(Ok(()))
+			} else if closed {
+				Poll::Ready(Err(closed_error()))
+			} else {
+				stream.read_waker = Some(cx.waker().clone());
+				Poll::Pending
+			};
+		}
+		let mut read = 0;
+		while buf.remaining() > 0 {
+			let chunk = match stream.recv_buf.front_mut() {
+				Some(chunk) => chunk,
+				None => break,
+			};
+			let len = chunk.len().min(buf.remaining());
+			buf.put_slice(&chunk[..len]);
+			chunk.advance(len);
+			if chunk.is_empty() {
+				stream.recv_buf.pop_front();
+			}
+			read += len;
+		}
+		stream.unacked += read as u32;
+		// Give the peer its window back in batches, rather than after every read.
+		if stream.unacked >= INITIAL_WINDOW / 2 && !stream.remote_closed {
+			let increment = std::mem::take(&mut stream.unacked);
+			stream.recv_window += increment;
+			let _ = self.tx.send(Frame::WindowUpdate {
+				id: self.id,
+				increment,
+			});
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncWrite for MuxStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, IoError>> {
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+		let mut shared = lock(&self.shared);
+		if shared.closed {
+			return Poll::Ready(Err(closed_error()));
+		}
+		let stream = match shared.streams.get_mut(&self.id) {
+			Some(stream) if !stream.local_closed => stream,
+			_ => {
+				return Poll::Ready(Err(IoError::new(
+					IoErrorKind::BrokenPipe,
+					"stream is closed",
+				)))
+			}
+		};
+		if stream.send_window == 0 {
+			stream.write_waker = Some(cx.waker().clone());
+			return Poll::Pending;
+		}
+		let len = buf.len().min(stream.send_window as usize).min(MAX_PAYLOAD);
+		stream.send_window -= len as u32;
+		self.tx
+			.send(Frame::Data {
+				id: self.id,
+				data: Bytes::copy_from_slice(&buf[..len]),
+			})
+			.map_err(|_| closed_error())?;
+		Poll::Ready(Ok(len))
+	}
+
+	// Frames are flushed as soon as the driver gets to them.
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		let mut shared = lock(&self.shared);
+		if let Some(stream) = shared.streams.get_mut(&self.id) {
+			if !stream.local_closed {
+				stream.local_closed = true;
+				let _ = self.tx.send(Frame::Close { id: self.id });
+			}
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl Drop for MuxStream {
+	fn drop(&mut self) {
+		let mut shared = lock(&self.shared);
+		if let Some(stream) = shared.streams.remove(&self.id) {
+			if !stream.local_closed {
+				let _ = self.tx.send(Frame::Close { id: self.id });
+			}
+		}
+	}
+}
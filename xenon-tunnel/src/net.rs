@@ -6,12 +6,9 @@
 use crate::SIZE_LIMIT;
 use anyhow::{Context, Result};
 use nano_leb128::ULEB128;
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub async fn recv_size(socket: &mut TcpStream) -> Option<usize> {
+pub async fn recv_size<S: AsyncRead + Unpin>(socket: &mut S) -> Option<usize> {
 	let mut len_buf = [0u8];
 	let mut buf = Vec::<u8>::new();
 	for _ in 0..std::mem::size_of::<u64>() {
@@ -28,7 +25,7 @@ pub async fn recv_size(socket: &mut TcpStream) -> Option<usize> {
 		.map(|(size, _)| u64::from(size) as usize)
 }
 
-pub async fn send_size(socket: &mut TcpStream, size: usize) -> Option<()> {
+pub async fn send_size<S: AsyncWrite + Unpin>(socket: &mut S, size: usize) -> Option<()> {
 	if size > SIZE_LIMIT {
 		return None;
 	}
@@ -45,7 +42,10 @@ pub async fn send_size(socket: &mut TcpStream, size: usize) -> Option<()> {
 }
 
 #[allow(clippy::needless_lifetimes)]
-pub async fn read_msg<'a>(socket: &mut TcpStream, buf: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+pub async fn read_msg<'a, S: AsyncRead + Unpin>(
+	socket: &mut S,
+	buf: &'a mut Vec<u8>,
+) -> Option<&'a [u8]> {
 	let size = recv_size(socket).await?;
 	if size > buf.len() {
 		buf.resize(size, 0);
@@ -56,7 +56,7 @@ pub async fn read_msg<'a>(socket: &mut TcpStream, buf: &'a mut Vec<u8>) -> Optio
 	}
 }
 
-pub async fn write_msg(socket: &mut TcpStream, buf: &[u8]) -> Result<()> {
+pub async fn write_msg<S: AsyncWrite + Unpin>(socket: &mut S, buf: &[u8]) -> Result<()> {
 	send_size(socket, buf.len())
 		.await
 		.context("failed to send size")?;
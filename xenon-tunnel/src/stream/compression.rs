@@ -0,0 +1,151 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+pub use crate::handshake::CompressionAlgorithm;
+use anyhow::Result;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+pub const DEFAULT_ZSTD_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// Parses a configured list of algorithms, in order of preference. An empty list means all of them.
+pub fn parse_preference(names: &[String]) -> Result<Vec<CompressionAlgorithm>> {
+	if names.is_empty() {
+		return Ok(CompressionAlgorithm::SUPPORTED.to_vec());
+	}
+	names.iter().map(|name| name.parse()).collect()
+}
+
+pub trait Compression: Send {
+	fn algorithm(&self) -> CompressionAlgorithm;
+
+	/// Compresses `input`, appending the result to `output`.
+	/// An error here isn't fatal, the frame is just sent uncompressed instead.
+	fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), IoError>;
+
+	/// Decompresses `input` into `output`, which is resized to `size`.
+	fn decompress(
+		&mut self,
+		input: &[u8],
+		output: &mut Vec<u8>,
+		size: usize,
+	) -> Result<(), IoError>;
+}
+
+pub fn from_algorithm(algorithm: CompressionAlgorithm, zstd_level: i32) -> Box<dyn Compression> {
+	match algorithm {
+		CompressionAlgorithm::Lz4 => Box::new(Lz4),
+		CompressionAlgorithm::Zstd => Box::new(Zstd::new(zstd_level)),
+		CompressionAlgorithm::None => Box::new(Passthrough),
+	}
+}
+
+// lz4_flex copies in blocks that can run past the end of the output, and checks there's this much spare capacity for it.
+const LZ4_DECOMPRESS_SLACK: usize = 4 + 24;
+
+pub struct Lz4;
+
+impl Compression for Lz4 {
+	fn algorithm(&self) -> CompressionAlgorithm {
+		CompressionAlgorithm::Lz4
+	}
+
+	fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), IoError> {
+		lz4_flex::compress_into(input, output);
+		Ok(())
+	}
+
+	fn decompress(
+		&mut self,
+		input: &[u8],
+		output: &mut Vec<u8>,
+		size: usize,
+	) -> Result<(), IoError> {
+		output.reserve(size.saturating_sub(output.len()) + LZ4_DECOMPRESS_SLACK);
+		output.resize(size, 0);
+		lz4_flex::decompress_into(input, output)
+			.map_err(|err| IoError::new(IoErrorKind::InvalidData, err.to_string()))
+	}
+}
+
+pub struct Zstd {
+	level: i32,
+	compressor: zstd::block::Compressor,
+	decompressor: zstd::block::Decompressor,
+}
+
+impl Zstd {
+	pub fn new(level: i32) -> Self {
+		Self {
+			level,
+			compressor: zstd::block::Compressor::new(),
+			decompressor: zstd::block::Decompressor::new(),
+		}
+	}
+}
+
+impl Compression for Zstd {
+	fn algorithm(&self) -> CompressionAlgorithm {
+		CompressionAlgorithm::Zstd
+	}
+
+	fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), IoError> {
+		// Anything that doesn't fit in the size of the input isn't worth sending compressed anyways.
+		let start = output.len();
+		output.resize(start + input.len(), 0);
+		match self
+			.compressor
+			.compress_to_buffer(input, &mut output[start..], self.level)
+		{
+			Ok(len) => {
+				output.truncate(start + len);
+				Ok(())
+			}
+			Err(err) => {
+				output.truncate(start);
+				Err(err)
+			}
+		}
+	}
+
+	fn decompress(
+		&mut self,
+		input: &[u8],
+		output: &mut Vec<u8>,
+		size: usize,
+	) -> Result<(), IoError> {
+		output.resize(size, 0);
+		let len = self.decompressor.decompress_to_buffer(input, output)?;
+		if len != size {
+			return Err(IoError::new(
+				IoErrorKind::InvalidData,
+				"decompressed size doesn't match header",
+			));
+		}
+		Ok(())
+	}
+}
+
+// Never compresses anything, so every frame is sent raw.
+pub struct Passthrough;
+
+impl Compression for Passthrough {
+	fn algorithm(&self) -> CompressionAlgorithm {
+		CompressionAlgorithm::None
+	}
+
+	fn compress(&mut self, _: &[u8], _: &mut Vec<u8>) -> Result<(), IoError> {
+		Err(IoError::new(
+			IoErrorKind::Unsupported,
+			"compression is disabled",
+		))
+	}
+
+	fn decompress(&mut self, _: &[u8], _: &mut Vec<u8>, _: usize) -> Result<(), IoError> {
+		Err(IoError::new(
+			IoErrorKind::InvalidData,
+			"received a compressed frame, but compression is disabled",
+		))
+	}
+}
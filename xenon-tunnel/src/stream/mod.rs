@@ -3,11 +3,13 @@
 	All rights reserved.
 */
 
+pub mod compression;
 pub mod snowfall;
 
+use bytes::Bytes;
 use futures::{ready, Sink, Stream};
 use std::{
-	io::{Cursor, Error as IoError},
+	io::Error as IoError,
 	pin::Pin,
 	task::{Context, Poll},
 };
@@ -17,27 +19,47 @@ use tokio::{
 };
 use tokio_util::{codec::Framed, io::StreamReader};
 
-pub struct EncryptedTcpStream {
-	inner: StreamReader<Framed<TcpStream, snowfall::SnowfallStream>, Cursor<Vec<u8>>>,
+/// Anything a tunnel can be carried over, so that callers can box it without caring whether it's multiplexed.
+pub trait AsyncIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncIo for T {}
+
+/// A Noise tunnel over any transport, be it TCP, a Unix socket, usbmuxd, or an in-memory pipe.
+pub struct EncryptedStream<T> {
+	inner: StreamReader<Framed<T, snowfall::SnowfallStream>, Bytes>,
 }
 
-impl EncryptedTcpStream {
-	pub fn new(snowfall: snow::TransportState, stream: TcpStream) -> Self {
+pub type EncryptedTcpStream = EncryptedStream<TcpStream>;
+
+impl<T: AsyncRead + AsyncWrite + Unpin> EncryptedStream<T> {
+	pub fn new(
+		snowfall: snow::TransportState,
+		stream: T,
+		compression: Box<dyn compression::Compression>,
+	) -> Self {
 		Self {
-			inner: StreamReader::new(Framed::new(stream, snowfall::SnowfallStream::new(snowfall))),
+			inner: StreamReader::new(Framed::new(
+				stream,
+				snowfall::SnowfallStream::new(snowfall, compression),
+			)),
 		}
 	}
+
+	/// Changes how often we switch keys for the frames we send. The peer follows along on its own.
+	pub fn set_rekey_policy(&mut self, policy: snowfall::RekeyPolicy) {
+		self.inner.get_mut().codec_mut().set_rekey_policy(policy);
+	}
 }
 
-impl Stream for EncryptedTcpStream {
-	type Item = Result<Cursor<Vec<u8>>, IoError>;
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for EncryptedStream<T> {
+	type Item = Result<Bytes, IoError>;
 
 	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
 		Pin::new(self.inner.get_mut()).poll_next(cx)
 	}
 }
 
-impl AsyncWrite for EncryptedTcpStream {
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<T> {
 	fn poll_write(
 		mut self: Pin<&mut Self>,
 		cx: &mut Context<'_>,
@@ -58,7 +80,7 @@ impl AsyncWrite for EncryptedTcpStream {
 	}
 }
 
-impl AsyncRead for EncryptedTcpStream {
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<T> {
 	fn poll_read(
 		mut self: Pin<&mut Self>,
 		cx: &mut Context<'_>,
@@ -3,122 +3,250 @@
 	All rights reserved.
 */
 
+use super::compression::{Compression, CompressionAlgorithm};
 use crate::SIZE_LIMIT;
 use anyhow::Result;
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use nano_leb128::{LEB128DecodeError, ULEB128};
 use snow::TransportState;
-use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+use std::{
+	io::{Error as IoError, ErrorKind as IoErrorKind},
+	time::{Duration, Instant},
+};
 use tokio_util::codec::{Decoder, Encoder};
 
+// The first byte of every decrypted frame says whether the rest of it is compressed,
+// or whether it's a control frame telling us the peer is about to switch keys.
+const FRAME_RAW: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+const FRAME_REKEY: u8 = 2;
+// Size of the ChaChaPoly authentication tag on every Noise message.
+const TAG_LEN: usize = 16;
+// The largest chunk of plaintext that still fits in one frame when sent raw,
+// as frames must be smaller than SIZE_LIMIT after the frame type and tag are added.
+pub const MAX_CHUNK_SIZE: usize = SIZE_LIMIT - 1 - TAG_LEN - 1;
+
+/// How often each side switches to a new key for the frames it sends.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+	pub messages: u64,
+	pub interval: Duration,
+}
+
+impl RekeyPolicy {
+	pub const NEVER: Self = Self {
+		messages: u64::MAX,
+		interval: Duration::MAX,
+	};
+}
+
+impl Default for RekeyPolicy {
+	fn default() -> Self {
+		Self {
+			// Roughly 64 GiB worth of full frames.
+			messages: 1 << 20,
+			interval: Duration::from_secs(60 * 60),
+		}
+	}
+}
+
 pub struct SnowfallStream {
 	snowfall: TransportState,
+	compression: Box<dyn Compression>,
+	rekey_policy: RekeyPolicy,
+	sent_since_rekey: u64,
+	last_rekey: Instant,
 	encryption_buf: Vec<u8>,
 	compression_buf: Vec<u8>,
+	decryption_buf: BytesMut,
 }
 
 impl SnowfallStream {
-	pub fn new(snowfall: TransportState) -> Self {
+	pub fn new(snowfall: TransportState, compression: Box<dyn Compression>) -> Self {
 		Self {
 			snowfall,
+			compression,
+			rekey_policy: RekeyPolicy::default(),
+			sent_since_rekey: 0,
+			last_rekey: Instant::now(),
 			encryption_buf: Vec::with_capacity(65535),
 			compression_buf: Vec::with_capacity(65535),
+			decryption_buf: BytesMut::with_capacity(65535),
 		}
 	}
-}
 
-impl Encoder<&[u8]> for SnowfallStream {
-	type Error = IoError;
+	pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+		self.rekey_policy = policy;
+	}
 
-	fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
-		// Resize the compression buffer to the size of a u64, which should be the upper limit of a ULEB128
-		self.compression_buf.resize(std::mem::size_of::<u64>(), 0);
-		// Encode the size of our uncompressed data as an ULEB128 into our compression buffer
-		ULEB128::from(item.len() as u64)
-			.write_into(&mut self.compression_buf)
-			.map(|len| self.compression_buf.truncate(len))
-			.map_err(|_| IoError::new(IoErrorKind::InvalidInput, "failed to encode ULEB128"))
-			.expect("failed to encode uleb128");
-		// Compress into our compression buffer, appending it to the dcompressed length
-		lz4_flex::compress_into(&item, &mut self.compression_buf);
+	fn rekey_due(&self) -> bool {
+		self.sent_since_rekey >= self.rekey_policy.messages
+			|| self.last_rekey.elapsed() >= self.rekey_policy.interval
+	}
+
+	// Tells the peer to switch keys, encrypted with the old key, then switches ourselves.
+	fn rekey(&mut self, dst: &mut BytesMut) -> Result<(), IoError> {
+		self.compression_buf.clear();
+		self.compression_buf.push(FRAME_REKEY);
+		self.encrypt_frame(dst)?;
+		self.snowfall.rekey_outgoing();
+		self.sent_since_rekey = 0;
+		self.last_rekey = Instant::now();
+		Ok(())
+	}
+
+	fn encode_frame(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), IoError> {
+		// Encode the size of our uncompressed data as an ULEB128, after the frame type
+		let mut size_header = [0u8; std::mem::size_of::<u64>()];
+		let size_header = ULEB128::from(item.len() as u64)
+			.write_into(&mut size_header)
+			.map(|len| &size_header[..len])
+			.map_err(|_| IoError::new(IoErrorKind::InvalidInput, "failed to encode ULEB128"))?;
+		self.compression_buf.clear();
+		self.compression_buf.push(FRAME_COMPRESSED);
+		self.compression_buf.extend_from_slice(size_header);
+		// Compress into our compression buffer, appending it to the decompressed length.
+		// If that fails or doesn't make it any smaller (i.e. photos and videos), send it raw instead.
+		let compressed = self.compression.algorithm() != CompressionAlgorithm::None
+			&& self
+				.compression
+				.compress(item, &mut self.compression_buf)
+				.is_ok() && self.compression_buf.len() <= item.len();
+		if !compressed {
+			self.compression_buf.clear();
+			self.compression_buf.push(FRAME_RAW);
+			self.compression_buf.extend_from_slice(item);
+		}
+		self.encrypt_frame(dst)
+	}
+
+	// Encrypts whatever frame is in the compression buffer, and writes it to `dst`.
+	fn encrypt_frame(&mut self, dst: &mut BytesMut) -> Result<(), IoError> {
 		// Resize the encryption buffer, to be the size of the compressed data plus the 16-byte NPF tag.
 		self.encryption_buf
-			.resize(self.compression_buf.len() + 16, 0);
+			.resize(self.compression_buf.len() + TAG_LEN, 0);
 		// Encrypt our now-compressed data, getting the slice of the encrypted+compressed data.
+		// This only fails if the nonce is exhausted, in which case the connection is done for.
 		let ec_req = self
 			.snowfall
 			.write_message(&self.compression_buf, &mut self.encryption_buf)
 			.map(|len| &self.encryption_buf[..len])
-			.map_err(|e| IoError::new(IoErrorKind::InvalidInput, e.to_string()))
-			.expect("failed to encrypt compressed data");
+			.map_err(|e| {
+				IoError::new(
+					IoErrorKind::Other,
+					format!("failed to encrypt frame: {}", e),
+				)
+			})?;
 		// Encode the size of our encrypted+compressed data into the output buffer.
 		let mut length_header = [0u8; std::mem::size_of::<u64>()];
 		let length_header = ULEB128::from(ec_req.len() as u64)
 			.write_into(&mut length_header)
 			.map(|len| &mut length_header[..len])
-			.map_err(|_| IoError::new(IoErrorKind::InvalidInput, "failed to encode ULEB128"))
-			.expect("failed to encode uleb128");
+			.map_err(|_| IoError::new(IoErrorKind::InvalidInput, "failed to encode ULEB128"))?;
 		// Now, copy our encrypted-compressed data into it.
+		dst.reserve(length_header.len() + ec_req.len());
 		dst.extend_from_slice(&length_header);
 		dst.extend_from_slice(ec_req);
 		Ok(())
 	}
 }
 
+impl Encoder<&[u8]> for SnowfallStream {
+	type Error = IoError;
+
+	fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+		// Noise messages can't be larger than 65535 bytes, so bigger writes are split across multiple frames.
+		for chunk in item.chunks(MAX_CHUNK_SIZE) {
+			if self.rekey_due() {
+				self.rekey(dst)?;
+			}
+			self.encode_frame(chunk, dst)?;
+			self.sent_since_rekey += 1;
+		}
+		Ok(())
+	}
+}
+
 impl Decoder for SnowfallStream {
-	type Item = Cursor<Vec<u8>>;
+	type Item = Bytes;
 	type Error = IoError;
 
 	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-		// Read the length, handling possible errors or invalid lengths.
-		let (length, offset) = match ULEB128::read_from(&src) {
-			Ok((length, offset)) => {
-				let length = u64::from(length) as usize;
-				match length {
-					0 => Err(IoError::new(IoErrorKind::InvalidData, "length is zero")),
-					SIZE_LIMIT..=usize::MAX => Err(IoError::new(
-						IoErrorKind::InvalidData,
-						"length exceeds 65535",
-					)),
-					_ => Ok((length as usize, offset)),
-				}?
+		loop {
+			// Read the length, handling possible errors or invalid lengths.
+			let (length, offset) = match ULEB128::read_from(&src) {
+				Ok((length, offset)) => {
+					let length = u64::from(length) as usize;
+					match length {
+						0 => Err(IoError::new(IoErrorKind::InvalidData, "length is zero")),
+						SIZE_LIMIT..=usize::MAX => Err(IoError::new(
+							IoErrorKind::InvalidData,
+							"length exceeds 65535",
+						)),
+						_ => Ok((length as usize, offset)),
+					}?
+				}
+				Err(err) => match err {
+					LEB128DecodeError::IntegerOverflow => {
+						return Err(IoError::new(IoErrorKind::InvalidData, "length overflowed"))
+					}
+					LEB128DecodeError::BufferOverflow => return Ok(None),
+				},
+			};
+			// If the remaining length isn't there, reserve the space needed for it and wait
+			if src.len() < offset + length {
+				src.reserve(offset + length - src.len());
+				return Ok(None);
+			}
+			// Alright, we have our whole message now. Split it off without copying.
+			src.advance(offset);
+			let data = src.split_to(length);
+			// snow can't decrypt in place, so this is the one copy every frame needs.
+			// The buffer's allocation gets reused once the previous frames are dropped.
+			self.decryption_buf.clear();
+			self.decryption_buf.resize(length, 0);
+			let len = self
+				.snowfall
+				.read_message(&data, &mut self.decryption_buf)
+				.map_err(|err| IoError::new(IoErrorKind::InvalidData, err.to_string()))?;
+			let mut decrypted_msg = self.decryption_buf.split_to(len);
+			// Check what kind of frame this is.
+			if decrypted_msg.is_empty() {
+				return Err(IoError::new(IoErrorKind::InvalidData, "frame is empty"));
 			}
-			Err(err) => match err {
-				LEB128DecodeError::IntegerOverflow => {
-					return Err(IoError::new(IoErrorKind::InvalidData, "length overflowed"))
+			let frame_type = decrypted_msg[0];
+			decrypted_msg.advance(1);
+			match frame_type {
+				// Raw frames are handed out as-is.
+				FRAME_RAW => return Ok(Some(decrypted_msg.freeze())),
+				FRAME_COMPRESSED => {
+					// Now, we read the decompressed size from the decrypted message.
+					let (decompressed_size, start_at) = ULEB128::read_from(&decrypted_msg)
+						.map(|(num, len)| (u64::from(num) as usize, len))
+						.map_err(IoError::from)?;
+					// The encoder never puts more than this in a frame, so don't let peers make us allocate more.
+					if decompressed_size > MAX_CHUNK_SIZE {
+						return Err(IoError::new(
+							IoErrorKind::InvalidData,
+							"decompressed size is too large",
+						));
+					}
+					// Alright, actually decompress everything after the decompression length part.
+					// Bytes takes ownership of the Vec's allocation, so there's no copy there.
+					let mut decompressed = Vec::with_capacity(decompressed_size);
+					self.compression.decompress(
+						&decrypted_msg[start_at..],
+						&mut decompressed,
+						decompressed_size,
+					)?;
+					// And we're done here! Return the decompressed data.
+					return Ok(Some(Bytes::from(decompressed)));
 				}
-				LEB128DecodeError::BufferOverflow => return Ok(None),
-			},
-		};
-		// If the remaining length isn't there, reserve the space needed for it and wait
-		if src.len() < offset + length {
-			src.reserve(offset + length - src.len());
-			return Ok(None);
+				// Everything after this was encrypted with the peer's next key.
+				// There's no data in it, so carry on with the next frame.
+				FRAME_REKEY => self.snowfall.rekey_incoming(),
+				_ => return Err(IoError::new(IoErrorKind::InvalidData, "unknown frame type")),
+			}
 		}
-		// Alright, we have our whole message now.
-		let data = src[offset..offset + length].to_vec();
-		src.advance(offset + length);
-		// Ensure our decryption buffer is the right size
-		self.encryption_buf.resize(length, 0);
-		let encryption_buf = &mut self.encryption_buf;
-		// Decrypt the message, putting it into our encryption buffer
-		let decrypted_msg = self
-			.snowfall
-			.read_message(&data, encryption_buf)
-			.map(|len| &encryption_buf[..len])
-			.map_err(|err| IoError::new(IoErrorKind::InvalidData, err.to_string()))?;
-		// Now, we read the decompressed size from the decrypted message.
-		let (decompressed_size, start_at) = ULEB128::read_from(decrypted_msg)
-			.map(|(num, len)| (u64::from(num) as usize, len))
-			.map_err(IoError::from)?;
-		// Get a slice of where the decompression length part ended
-		let compressed_msg = &decrypted_msg[start_at..];
-		// Resize our decompression buffer to the size of the decompressed content.
-		self.compression_buf.resize(decompressed_size, 0);
-		// Alright, actually decompress.
-		lz4_flex::decompress_into(&compressed_msg, &mut self.compression_buf)
-			.map_err(|err| IoError::new(IoErrorKind::InvalidData, err.to_string()))?;
-		// And we're done here! Return the decompressed data.
-		Ok(Some(Cursor::new(self.compression_buf.clone())))
 	}
 }
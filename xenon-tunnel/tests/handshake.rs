@@ -0,0 +1,120 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use std::time::Duration;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+use xenon_tunnel::{
+	handshake::{
+		self, Features, Hello, HelloReply, Rejection, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC,
+		PROTOCOL_VERSION,
+	},
+	net,
+};
+
+async fn tcp_sockets() -> (TcpStream, TcpStream) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+	(client.unwrap(), server.unwrap().0)
+}
+
+// Speaks to the server the way a client at `version` would, returning its reply.
+async fn hello_at(socket: &mut TcpStream, version: u16, timestamp: u64) -> HelloReply {
+	socket.write_all(PROTOCOL_MAGIC).await.unwrap();
+	socket.write_all(&version.to_be_bytes()).await.unwrap();
+	let hello = rmp_serde::to_vec_named(&Hello {
+		version,
+		features: Features::default(),
+		timestamp,
+	})
+	.unwrap();
+	net::write_msg(socket, &hello).await.unwrap();
+
+	let mut magic = [0u8; PROTOCOL_MAGIC.len() + 2];
+	socket.read_exact(&mut magic).await.unwrap();
+	assert_eq!(&magic[..PROTOCOL_MAGIC.len()], PROTOCOL_MAGIC);
+	assert_eq!(
+		u16::from_be_bytes([magic[PROTOCOL_MAGIC.len()], magic[PROTOCOL_MAGIC.len() + 1]]),
+		PROTOCOL_VERSION
+	);
+	let mut buf = Vec::new();
+	rmp_serde::from_slice(net::read_msg(socket, &mut buf).await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn current_version() {
+	let (mut client, mut server) = tcp_sockets().await;
+	let (client, server) = tokio::join!(
+		handshake::send_hello(&mut client, Features::default()),
+		handshake::accept_hello(&mut server, Features::default(), Duration::from_secs(0))
+	);
+	let (client, server) = (client.unwrap(), server.unwrap());
+	assert_eq!(client.version, PROTOCOL_VERSION);
+	assert_eq!(server.version, PROTOCOL_VERSION);
+	assert_eq!(client.prologue, server.prologue);
+	assert_ne!(server.rekey_policy().messages, u64::MAX);
+}
+
+#[tokio::test]
+async fn oldest_supported_version() {
+	assert!(MIN_PROTOCOL_VERSION < PROTOCOL_VERSION);
+	let (mut client, mut server) = tcp_sockets().await;
+	let (reply, negotiated) = tokio::join!(
+		hello_at(&mut client, MIN_PROTOCOL_VERSION, 0),
+		handshake::accept_hello(&mut server, Features::default(), Duration::from_secs(0))
+	);
+	match reply {
+		HelloReply::Accepted(accepted) => assert_eq!(accepted.version, MIN_PROTOCOL_VERSION),
+		HelloReply::Rejected(rejection) => panic!("rejected: {}", rejection),
+	}
+	let negotiated = negotiated.unwrap();
+	assert_eq!(negotiated.version, MIN_PROTOCOL_VERSION);
+	// Rekey frames are newer than this version, so they must never be sent.
+	assert_eq!(negotiated.rekey_policy().messages, u64::MAX);
+}
+
+#[tokio::test]
+async fn unsupported_version() {
+	let (mut client, mut server) = tcp_sockets().await;
+	let (reply, negotiated) = tokio::join!(
+		hello_at(&mut client, MIN_PROTOCOL_VERSION - 1, 0),
+		handshake::accept_hello(&mut server, Features::default(), Duration::from_secs(0))
+	);
+	assert!(negotiated.is_err());
+	match reply {
+		HelloReply::Rejected(rejection) => assert_eq!(
+			rejection,
+			Rejection::VersionMismatch {
+				version: MIN_PROTOCOL_VERSION - 1,
+				min: MIN_PROTOCOL_VERSION,
+				max: PROTOCOL_VERSION,
+			}
+		),
+		HelloReply::Accepted(accepted) => panic!("accepted version {}", accepted.version),
+	}
+}
+
+#[tokio::test]
+async fn timestamp_far_in_the_future() {
+	let (mut client, mut server) = tcp_sockets().await;
+	let (reply, negotiated) = tokio::join!(
+		hello_at(&mut client, PROTOCOL_VERSION, u64::MAX),
+		handshake::accept_hello(&mut server, Features::default(), Duration::from_secs(30))
+	);
+	assert!(negotiated.is_err());
+	match reply {
+		HelloReply::Rejected(rejection) => assert_eq!(
+			rejection,
+			Rejection::ClockSkew {
+				skew_ms: i64::MIN,
+				tolerance_ms: 30_000,
+			}
+		),
+		HelloReply::Accepted(accepted) => panic!("accepted version {}", accepted.version),
+	}
+}
@@ -0,0 +1,145 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use xenon_tunnel::{
+	control::{self, ControlMessage},
+	mux::{Incoming, Multiplexer, Role, Service, INITIAL_WINDOW, MAX_STREAMS},
+};
+
+fn pair() -> ((Multiplexer, Incoming), (Multiplexer, Incoming)) {
+	let (client_io, server_io) = duplex(64 * 1024);
+	let (client, client_incoming, client_driver) = Multiplexer::new(client_io, Role::Initiator);
+	let (server, server_incoming, server_driver) = Multiplexer::new(server_io, Role::Responder);
+	tokio::spawn(client_driver);
+	tokio::spawn(server_driver);
+	((client, client_incoming), (server, server_incoming))
+}
+
+fn test_data(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn open_and_echo() {
+	let ((client, _), (_, mut incoming)) = pair();
+	let mut stream = client.open(Service::Http).unwrap();
+	stream.write_all(b"hello").await.unwrap();
+
+	let (service, mut accepted) = incoming.accept().await.unwrap();
+	assert_eq!(service, Service::Http);
+	assert_eq!(accepted.id(), stream.id());
+	let mut buf = [0u8; 5];
+	accepted.read_exact(&mut buf).await.unwrap();
+	assert_eq!(&buf, b"hello");
+	accepted.write_all(&buf).await.unwrap();
+
+	let mut echoed = [0u8; 5];
+	stream.read_exact(&mut echoed).await.unwrap();
+	assert_eq!(&echoed, b"hello");
+}
+
+#[tokio::test]
+async fn streams_are_independent() {
+	let ((client, _), (_, mut incoming)) = pair();
+	let mut first = client.open(Service::Http).unwrap();
+	let mut second = client.open(Service::Control).unwrap();
+	assert_ne!(first.id(), second.id());
+	second.write_all(b"second").await.unwrap();
+	first.write_all(b"first").await.unwrap();
+
+	let (service, mut first_accepted) = incoming.accept().await.unwrap();
+	assert_eq!(service, Service::Http);
+	let (service, mut second_accepted) = incoming.accept().await.unwrap();
+	assert_eq!(service, Service::Control);
+	let mut buf = [0u8; 6];
+	second_accepted.read_exact(&mut buf).await.unwrap();
+	assert_eq!(&buf, b"second");
+	let mut buf = [0u8; 5];
+	first_accepted.read_exact(&mut buf).await.unwrap();
+	assert_eq!(&buf, b"first");
+}
+
+#[tokio::test]
+async fn transfers_more_than_the_window() {
+	let ((client, _), (_, mut incoming)) = pair();
+	let mut stream = client.open(Service::Http).unwrap();
+	let data = test_data(INITIAL_WINDOW as usize * 8 + 13);
+	let expected = data.clone();
+	let writer = tokio::spawn(async move {
+		stream.write_all(&data).await.unwrap();
+		stream.shutdown().await.unwrap();
+		stream
+	});
+
+	let (_, mut accepted) = incoming.accept().await.unwrap();
+	let mut received = Vec::new();
+	accepted.read_to_end(&mut received).await.unwrap();
+	writer.await.unwrap();
+	assert!(received == expected, "data was corrupted in transit");
+}
+
+#[tokio::test]
+async fn dropping_a_stream_closes_it() {
+	let ((client, _), (_, mut incoming)) = pair();
+	let mut stream = client.open(Service::Http).unwrap();
+	stream.write_all(b"bye").await.unwrap();
+	drop(stream);
+
+	let (_, mut accepted) = incoming.accept().await.unwrap();
+	let mut received = Vec::new();
+	accepted.read_to_end(&mut received).await.unwrap();
+	assert_eq!(received, b"bye");
+}
+
+#[tokio::test]
+async fn opening_too_many_streams_fails() {
+	let ((client, _), (_, _incoming)) = pair();
+	let streams = (0..MAX_STREAMS)
+		.map(|_| client.open(Service::Http).unwrap())
+		.collect::<Vec<_>>();
+	assert!(client.open(Service::Http).is_err());
+	drop(streams);
+	assert!(client.open(Service::Http).is_ok());
+}
+
+#[tokio::test]
+async fn peers_opening_too_many_streams_are_reset() {
+	let (mut peer, io) = duplex(64 * 1024);
+	let (_server, _incoming, driver) = Multiplexer::new(io, Role::Responder);
+	tokio::spawn(driver);
+	// Nothing accepts these, so they all stay open.
+	for index in 0..=MAX_STREAMS as u32 {
+		let mut open = vec![0u8];
+		open.extend_from_slice(&(index * 2 + 1).to_be_bytes());
+		open.extend_from_slice(&2u32.to_be_bytes());
+		open.extend_from_slice(&0u16.to_be_bytes());
+		peer.write_all(&open).await.unwrap();
+	}
+
+	let mut close = [0u8; 9];
+	peer.read_exact(&mut close).await.unwrap();
+	assert_eq!(close[0], 3);
+	assert_eq!(&close[1..5], &(MAX_STREAMS as u32 * 2 + 1).to_be_bytes());
+}
+
+#[tokio::test]
+async fn control_stream_answers_pings() {
+	let ((client, _), (_, mut incoming)) = pair();
+	let mut stream = client.open(Service::Control).unwrap();
+	tokio::spawn(async move {
+		let (_, accepted) = incoming.accept().await.unwrap();
+		control::respond(accepted).await.unwrap();
+	});
+	for sequence in 1..=3 {
+		control::write_message(&mut stream, &ControlMessage::Ping(sequence))
+			.await
+			.unwrap();
+		assert_eq!(
+			control::read_message(&mut stream).await.unwrap(),
+			Some(ControlMessage::Pong(sequence))
+		);
+	}
+}
@@ -0,0 +1,276 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+use snow::Builder;
+use std::time::Duration;
+use tokio::{
+	io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+use xenon_tunnel::{
+	net,
+	stream::{
+		compression::{self, CompressionAlgorithm, DEFAULT_ZSTD_LEVEL},
+		snowfall::{RekeyPolicy, MAX_CHUNK_SIZE},
+	},
+	EncryptedStream, EncryptedTcpStream, NOISE_PARAMS,
+};
+
+async fn tcp_sockets() -> (TcpStream, TcpStream) {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+	(client.unwrap(), server.unwrap().0)
+}
+
+// Runs an XK handshake over the given sockets, then wraps them both in encrypted streams.
+async fn encrypt<T>(
+	mut client_socket: T,
+	mut server_socket: T,
+	algorithm: CompressionAlgorithm,
+) -> (EncryptedStream<T>, EncryptedStream<T>)
+where
+	T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let server_keys = Builder::new(NOISE_PARAMS.clone())
+		.generate_keypair()
+		.unwrap();
+	let client_keys = Builder::new(NOISE_PARAMS.clone())
+		.generate_keypair()
+		.unwrap();
+
+	let server_private = server_keys.private.clone();
+	let server = tokio::spawn(async move {
+		let mut buf = Vec::new();
+		let mut handshake_buf = vec![0u8; 65535];
+		let mut responder = Builder::new(NOISE_PARAMS.clone())
+			.local_private_key(&server_private)
+			.build_responder()
+			.unwrap();
+		let msg = net::read_msg(&mut server_socket, &mut buf).await.unwrap();
+		responder.read_message(msg, &mut handshake_buf).unwrap();
+		let len = responder.write_message(&[], &mut handshake_buf).unwrap();
+		net::write_msg(&mut server_socket, &handshake_buf[..len])
+			.await
+			.unwrap();
+		let msg = net::read_msg(&mut server_socket, &mut buf).await.unwrap();
+		responder.read_message(msg, &mut handshake_buf).unwrap();
+		(responder.into_transport_mode().unwrap(), server_socket)
+	});
+
+	let mut buf = Vec::new();
+	let mut handshake_buf = vec![0u8; 65535];
+	let mut initiator = Builder::new(NOISE_PARAMS.clone())
+		.local_private_key(&client_keys.private)
+		.remote_public_key(&server_keys.public)
+		.build_initiator()
+		.unwrap();
+	let len = initiator.write_message(&[], &mut handshake_buf).unwrap();
+	net::write_msg(&mut client_socket, &handshake_buf[..len])
+		.await
+		.unwrap();
+	let msg = net::read_msg(&mut client_socket, &mut buf).await.unwrap();
+	initiator.read_message(msg, &mut handshake_buf).unwrap();
+	let len = initiator.write_message(&[], &mut handshake_buf).unwrap();
+	net::write_msg(&mut client_socket, &handshake_buf[..len])
+		.await
+		.unwrap();
+
+	let (server_state, server_socket) = server.await.unwrap();
+	(
+		EncryptedStream::new(
+			initiator.into_transport_mode().unwrap(),
+			client_socket,
+			compression::from_algorithm(algorithm, DEFAULT_ZSTD_LEVEL),
+		),
+		EncryptedStream::new(
+			server_state,
+			server_socket,
+			compression::from_algorithm(algorithm, DEFAULT_ZSTD_LEVEL),
+		),
+	)
+}
+
+async fn pair(algorithm: CompressionAlgorithm) -> (EncryptedTcpStream, EncryptedTcpStream) {
+	let (client, server) = tcp_sockets().await;
+	encrypt(client, server, algorithm).await
+}
+
+// Half compressible text, half xorshift noise, so both raw and compressed frames get sent.
+fn test_data(len: usize) -> Vec<u8> {
+	let mut state = 0x2545_f491_4f6c_dd1d_u64;
+	(0..len)
+		.map(|i| {
+			if (i / 4096) % 2 == 0 {
+				b"xenon"[i % 5]
+			} else {
+				state ^= state << 13;
+				state ^= state >> 7;
+				state ^= state << 17;
+				state as u8
+			}
+		})
+		.collect()
+}
+
+async fn round_trip(algorithm: CompressionAlgorithm, len: usize) {
+	let (client, server) = pair(algorithm).await;
+	round_trip_over(client, server, len).await;
+}
+
+async fn round_trip_over<T>(
+	mut client: EncryptedStream<T>,
+	mut server: EncryptedStream<T>,
+	len: usize,
+) where
+	T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let data = test_data(len);
+	let expected = data.clone();
+	let writer = tokio::spawn(async move {
+		client.write_all(&data).await.unwrap();
+		client.flush().await.unwrap();
+		client
+	});
+	let mut received = vec![0u8; len];
+	server.read_exact(&mut received).await.unwrap();
+	writer.await.unwrap();
+	assert!(received == expected, "data was corrupted in transit");
+}
+
+#[tokio::test]
+async fn write_one_byte() {
+	for algorithm in CompressionAlgorithm::SUPPORTED {
+		round_trip(*algorithm, 1).await;
+	}
+}
+
+#[tokio::test]
+async fn write_noise_message_limit() {
+	for algorithm in CompressionAlgorithm::SUPPORTED {
+		round_trip(*algorithm, 65535).await;
+	}
+}
+
+#[tokio::test]
+async fn write_multiple_megabytes() {
+	for algorithm in CompressionAlgorithm::SUPPORTED {
+		round_trip(*algorithm, 8 * 1024 * 1024 + 7).await;
+	}
+}
+
+#[tokio::test]
+async fn write_full_compressible_lz4_chunk() {
+	let (mut client, mut server) = pair(CompressionAlgorithm::Lz4).await;
+	let data = vec![b'x'; MAX_CHUNK_SIZE];
+	let mut received = vec![0u8; data.len()];
+	let (sent, read) = tokio::join!(
+		async {
+			client.write_all(&data).await?;
+			client.flush().await
+		},
+		server.read_exact(&mut received)
+	);
+	sent.unwrap();
+	read.unwrap();
+	assert!(received == data, "data was corrupted in transit");
+}
+
+#[tokio::test]
+async fn write_both_directions() {
+	let (mut client, mut server) = pair(CompressionAlgorithm::Lz4).await;
+	let data = test_data(200_000);
+	let mut received = vec![0u8; data.len()];
+	let (sent, read) = tokio::join!(
+		async {
+			client.write_all(&data).await?;
+			client.flush().await
+		},
+		server.read_exact(&mut received)
+	);
+	sent.unwrap();
+	read.unwrap();
+	let mut echoed = vec![0u8; data.len()];
+	let (sent, read) = tokio::join!(
+		async {
+			server.write_all(&received).await?;
+			server.flush().await
+		},
+		client.read_exact(&mut echoed)
+	);
+	sent.unwrap();
+	read.unwrap();
+	assert!(echoed == data, "data was corrupted in transit");
+}
+
+#[tokio::test]
+async fn rekey_by_message_count() {
+	let (mut client, mut server) = pair(CompressionAlgorithm::Lz4).await;
+	let policy = RekeyPolicy {
+		messages: 3,
+		interval: Duration::from_secs(60 * 60),
+	};
+	client.set_rekey_policy(policy);
+	server.set_rekey_policy(policy);
+	let data = test_data(1024 * 1024);
+	let mut received = vec![0u8; data.len()];
+	let (sent, read) = tokio::join!(
+		async {
+			client.write_all(&data).await?;
+			client.flush().await
+		},
+		server.read_exact(&mut received)
+	);
+	sent.unwrap();
+	read.unwrap();
+	assert!(received == data, "data was corrupted in transit");
+	let mut echoed = vec![0u8; data.len()];
+	let (sent, read) = tokio::join!(
+		async {
+			server.write_all(&received).await?;
+			server.flush().await
+		},
+		client.read_exact(&mut echoed)
+	);
+	sent.unwrap();
+	read.unwrap();
+	assert!(echoed == data, "data was corrupted in transit");
+}
+
+#[tokio::test]
+async fn rekey_by_elapsed_time() {
+	let (mut client, mut server) = pair(CompressionAlgorithm::None).await;
+	client.set_rekey_policy(RekeyPolicy {
+		messages: u64::MAX,
+		interval: Duration::from_millis(10),
+	});
+	for _ in 0..5 {
+		let data = test_data(20_000);
+		client.write_all(&data).await.unwrap();
+		client.flush().await.unwrap();
+		let mut received = vec![0u8; data.len()];
+		server.read_exact(&mut received).await.unwrap();
+		assert!(received == data, "data was corrupted in transit");
+		tokio::time::sleep(Duration::from_millis(20)).await;
+	}
+}
+
+#[tokio::test]
+async fn write_over_duplex_pipe() {
+	for algorithm in CompressionAlgorithm::SUPPORTED {
+		// Deliberately smaller than a frame, so the pipe fills up mid-frame.
+		let (client, server) = duplex(4096);
+		let (client, server) = encrypt(client, server, *algorithm).await;
+		round_trip_over(client, server, 1024 * 1024 + 3).await;
+	}
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn write_over_unix_socket() {
+	let (client, server) = tokio::net::UnixStream::pair().unwrap();
+	let (client, server) = encrypt(client, server, CompressionAlgorithm::Zstd).await;
+	round_trip_over(client, server, 1024 * 1024 + 3).await;
+}
@@ -0,0 +1,117 @@
+/*
+	Copyright (c) aspen 2021
+	All rights reserved.
+*/
+
+#![deny(
+	clippy::complexity,
+	clippy::correctness,
+	clippy::perf,
+	clippy::style,
+	unsafe_code
+)]
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::UnixStream,
+};
+use xenon_config::{
+	decode_ipc_message, encode_ipc_message, ipc_message_len, IpcCommand, IpcRequest, IpcResponse,
+	IPC_HEADER_LEN,
+};
+
+const DEFAULT_SOCKET: &str = "/tmp/me.aspenuwu.xenon.sock";
+
+/// Administers a running xenon-server over its IPC socket.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "xenonctl")]
+struct Opt {
+	/// The server's IPC socket, if it was started with --ipc-socket.
+	#[structopt(long, parse(from_os_str), default_value = DEFAULT_SOCKET)]
+	socket: PathBuf,
+	#[structopt(subcommand)]
+	command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+	/// Shows the server's version, public key and what it's serving.
+	Status,
+	/// Lists the mounts that are being served.
+	ListMounts,
+	/// Reloads mounts.json.
+	ReloadMounts,
+	/// Generates a new keypair. Every client has to be paired again afterwards.
+	RegenerateKeys,
+	/// Prints the server's public key.
+	Pubkey,
+	/// Lists app bundles that can be mounted.
+	Bundles,
+	/// Lists iCloud Drive bundles that can be mounted.
+	IcloudBundles,
+	/// Prints a client connection config for this server.
+	GenerateConfig,
+	/// Lists clients that are allowed to connect.
+	ListClients,
+	/// Stops a client from connecting, by its base64 public key.
+	RevokeClient { pubkey: String },
+	/// Pairs with a client, given the contents of its QR code.
+	Pair { qr_code: String },
+}
+
+impl Command {
+	fn to_ipc(&self) -> IpcCommand {
+		match self {
+			Command::Status => IpcCommand::Status,
+			Command::ListMounts => IpcCommand::ListMounts,
+			Command::ReloadMounts => IpcCommand::ReloadMounts,
+			Command::RegenerateKeys => IpcCommand::RegenerateKeys,
+			Command::Pubkey => IpcCommand::Pubkey,
+			Command::Bundles => IpcCommand::Bundles,
+			Command::IcloudBundles => IpcCommand::IcloudBundles,
+			Command::GenerateConfig => IpcCommand::GenerateConfig,
+			Command::ListClients => IpcCommand::ListClients,
+			Command::RevokeClient { pubkey } => IpcCommand::RevokeClient {
+				pubkey: pubkey.clone(),
+			},
+			Command::Pair { qr_code } => IpcCommand::Pair {
+				qr_code: qr_code.trim().to_string(),
+			},
+		}
+	}
+}
+
+async fn send_command(socket: &PathBuf, command: IpcCommand) -> Result<Value> {
+	let mut stream = UnixStream::connect(socket)
+		.await
+		.with_context(|| format!("failed to connect to {}", socket.display()))?;
+	stream
+		.write_all(&encode_ipc_message(&IpcRequest::new(command))?)
+		.await
+		.context("failed to send command")?;
+	let mut header = [0u8; IPC_HEADER_LEN];
+	stream
+		.read_exact(&mut header)
+		.await
+		.context("failed to read reply")?;
+	let mut json = vec![0u8; ipc_message_len(&header)?];
+	stream
+		.read_exact(&mut json)
+		.await
+		.context("failed to read reply")?;
+	decode_ipc_message::<IpcResponse>(&json)?.into_result()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+	let opt = Opt::from_args();
+	match send_command(&opt.socket, opt.command.to_ipc()).await? {
+		Value::String(reply) => println!("{}", reply.trim_end()),
+		reply => println!("{}", serde_json::to_string_pretty(&reply)?),
+	}
+	Ok(())
+}